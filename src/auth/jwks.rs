@@ -54,6 +54,10 @@ pub struct JwksAuth {
 pub struct JwksAuthList(Vec<JwksAuth>);
 
 impl JwksAuthList {
+    pub fn urls(&self) -> Vec<&Url> {
+        self.0.iter().map(|auth| &auth.url).collect()
+    }
+
     pub async fn authorize(
         &self,
         headers: &HeaderMap,
@@ -0,0 +1,402 @@
+use hyper::HeaderMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+use crate::health::HealthCheck;
+use crate::sticky::{self, Affinity, HashKey};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    Weighted,
+    RandomTwo,
+    LeastRequests,
+    ConsistentHash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(untagged)]
+pub enum Member {
+    Url(Url),
+    Weighted {
+        url: Url,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Member {
+    pub fn url(&self) -> &Url {
+        match self {
+            Member::Url(url) | Member::Weighted { url, .. } => url,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            Member::Url(_) => 1,
+            Member::Weighted { weight, .. } => *weight,
+        }
+    }
+}
+
+// Selection state is shared between clones of the endpoint, so it survives caching
+#[derive(Debug, Default)]
+pub struct BalanceState {
+    next: AtomicUsize,
+    weights: Mutex<Vec<i64>>,
+    outstanding: Mutex<Vec<usize>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LoadBalance {
+    #[serde(default)]
+    pub balance: Strategy,
+    pub members: Vec<Member>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_on: Option<HashKey>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub affinity: Option<Affinity>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+    #[serde(skip)]
+    state: Arc<BalanceState>,
+}
+
+impl Hash for LoadBalance {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.balance.hash(state);
+        self.members.hash(state);
+        self.hash_on.hash(state);
+        self.affinity.hash(state);
+        self.health_check.hash(state);
+    }
+}
+
+// Tracks an in flight request against a member, until dropped
+pub struct Outstanding {
+    state: Arc<BalanceState>,
+    index: usize,
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        let mut outstanding = self.state.outstanding.lock().unwrap();
+        if let Some(count) = outstanding.get_mut(self.index) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl LoadBalance {
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    // Pick a healthy member based on the strategy, counting it as outstanding until the guard
+    // drops. If no members are healthy, all members are considered. Clients pinned by an
+    // affinity cookie stay on their member while it is healthy.
+    pub fn select<F: Fn(&Url) -> bool>(
+        &self,
+        is_healthy: F,
+        headers: &HeaderMap,
+        client_addr: &SocketAddr,
+    ) -> (&Url, Outstanding) {
+        let mut candidates: Vec<usize> = (0..self.members.len())
+            .filter(|i| is_healthy(self.members[*i].url()))
+            .collect();
+        if candidates.is_empty() {
+            log::warn!("\"No healthy members for {}, using all members\"", self);
+            candidates = (0..self.members.len()).collect();
+        }
+
+        let pinned = self
+            .affinity
+            .as_ref()
+            .and_then(|affinity| affinity.requested(headers))
+            .and_then(|id| {
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|i| Affinity::member_id(self.members[*i].url()) == id)
+            });
+
+        let index = match (pinned, self.balance) {
+            (Some(index), _) => index,
+            (None, Strategy::RoundRobin) => candidates[self.round_robin(candidates.len())],
+            (None, Strategy::Weighted) => self.weighted(&candidates),
+            (None, Strategy::RandomTwo) => self.random_two(&candidates),
+            (None, Strategy::LeastRequests) => self.least_requests(&candidates),
+            (None, Strategy::ConsistentHash) => {
+                match self
+                    .hash_on
+                    .as_ref()
+                    .and_then(|k| k.key(headers, client_addr))
+                {
+                    Some(key) => self.consistent_hash(&candidates, &key),
+                    // Requests without a hash key are spread round robin
+                    None => candidates[self.round_robin(candidates.len())],
+                }
+            }
+        };
+        log::debug!(
+            "\"Load balancer picked {} using {}\"",
+            self.members[index].url(),
+            self.balance
+        );
+
+        let mut outstanding = self.state.outstanding.lock().unwrap();
+        outstanding.resize(self.members.len(), 0);
+        outstanding[index] += 1;
+
+        (
+            self.members[index].url(),
+            Outstanding {
+                state: self.state.clone(),
+                index,
+            },
+        )
+    }
+
+    fn round_robin(&self, len: usize) -> usize {
+        self.state.next.fetch_add(1, Ordering::Relaxed) % len
+    }
+
+    // Smooth weighted round robin, which spreads out picks of heavier members
+    fn weighted(&self, candidates: &[usize]) -> usize {
+        let mut weights = self.state.weights.lock().unwrap();
+        weights.resize(self.members.len(), 0);
+
+        let total: i64 = candidates
+            .iter()
+            .map(|i| self.members[*i].weight() as i64)
+            .sum();
+        let mut best = candidates[0];
+        for index in candidates {
+            weights[*index] += self.members[*index].weight() as i64;
+            if weights[*index] > weights[best] {
+                best = *index;
+            }
+        }
+        weights[best] -= total;
+        best
+    }
+
+    // Pick two members at random, and use the one with fewer outstanding requests
+    fn random_two(&self, candidates: &[usize]) -> usize {
+        let len = candidates.len();
+        if len == 1 {
+            return candidates[0];
+        }
+
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..len);
+        let second = (first + rng.gen_range(1..len)) % len;
+
+        let outstanding = self.outstanding();
+        if outstanding[candidates[second]] < outstanding[candidates[first]] {
+            candidates[second]
+        } else {
+            candidates[first]
+        }
+    }
+
+    // Start from a rotating offset, so ties don't always go to the first member
+    fn least_requests(&self, candidates: &[usize]) -> usize {
+        let len = candidates.len();
+        let offset = self.round_robin(len);
+        let outstanding = self.outstanding();
+        (0..len)
+            .map(|i| candidates[(offset + i) % len])
+            .min_by_key(|i| outstanding[*i])
+            .unwrap_or(candidates[0])
+    }
+
+    // Pick the member with the highest rendezvous score for the key
+    fn consistent_hash(&self, candidates: &[usize], key: &str) -> usize {
+        candidates
+            .iter()
+            .copied()
+            .map(|i| {
+                let member = &self.members[i];
+                (i, sticky::score(key, member.url(), member.weight()))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+            .unwrap_or(candidates[0])
+    }
+
+    fn outstanding(&self) -> Vec<usize> {
+        let mut outstanding = self.state.outstanding.lock().unwrap().clone();
+        outstanding.resize(self.members.len(), 0);
+        outstanding
+    }
+
+    pub fn path(&self) -> &str {
+        self.members.first().map(|m| m.url().path()).unwrap_or("/")
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Strategy::RoundRobin => f.write_str("round_robin"),
+            Strategy::Weighted => f.write_str("weighted"),
+            Strategy::RandomTwo => f.write_str("random_two"),
+            Strategy::LeastRequests => f.write_str("least_requests"),
+            Strategy::ConsistentHash => f.write_str("consistent_hash"),
+        }
+    }
+}
+
+impl fmt::Display for LoadBalance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let members: Vec<String> = self.members.iter().map(|m| m.url().to_string()).collect();
+        write!(f, "{}[{}]", self.balance, members.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn balance(strategy: Strategy, members: &[(&str, u32)]) -> LoadBalance {
+        LoadBalance {
+            balance: strategy,
+            members: members
+                .iter()
+                .map(|(url, weight)| Member::Weighted {
+                    url: Url::parse(url).unwrap(),
+                    weight: *weight,
+                })
+                .collect(),
+            hash_on: Some(HashKey::Header("x-user".to_string())),
+            affinity: None,
+            health_check: None,
+            state: Arc::default(),
+        }
+    }
+
+    fn client() -> SocketAddr {
+        "192.0.2.1:4000".parse().unwrap()
+    }
+
+    fn pick(balance: &LoadBalance, headers: &HeaderMap) -> String {
+        balance.select(|_| true, headers, &client()).0.to_string()
+    }
+
+    fn user(id: usize) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-user", id.to_string().parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn weighted_follows_weights() {
+        let balance = balance(
+            Strategy::Weighted,
+            &[("http://a:80", 5), ("http://b:80", 1), ("http://c:80", 1)],
+        );
+        let mut picks: HashMap<String, usize> = HashMap::new();
+        for _ in 0..70 {
+            *picks.entry(pick(&balance, &HeaderMap::new())).or_default() += 1;
+        }
+        assert_eq!(picks["http://a/"], 50);
+        assert_eq!(picks["http://b/"], 10);
+        assert_eq!(picks["http://c/"], 10);
+    }
+
+    #[test]
+    fn consistent_hash_keeps_keys_when_member_removed() {
+        let members = [("http://a:80", 1), ("http://b:80", 1), ("http://c:80", 1)];
+        let all = balance(Strategy::ConsistentHash, &members);
+        let fewer = balance(Strategy::ConsistentHash, &members[..2]);
+
+        let mut moved = 0;
+        for id in 0..300 {
+            let before = pick(&all, &user(id));
+            assert_eq!(before, pick(&all, &user(id)));
+            let after = pick(&fewer, &user(id));
+            if before == "http://c/" {
+                moved += 1;
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+        // Roughly a third of the keys were on the removed member
+        assert!((50..150).contains(&moved), "{} keys moved", moved);
+    }
+
+    #[test]
+    fn random_two_avoids_busier_member() {
+        let balance = balance(
+            Strategy::RandomTwo,
+            &[("http://a:80", 1), ("http://b:80", 1)],
+        );
+        let (busy, _guard) = balance.select(|_| true, &HeaderMap::new(), &client());
+        let busy = busy.to_string();
+        for _ in 0..20 {
+            assert_ne!(pick(&balance, &HeaderMap::new()), busy);
+        }
+    }
+
+    #[test]
+    fn least_requests_picks_fewest_outstanding() {
+        let balance = balance(
+            Strategy::LeastRequests,
+            &[("http://a:80", 1), ("http://b:80", 1), ("http://c:80", 1)],
+        );
+        *balance.state.outstanding.lock().unwrap() = vec![2, 0, 1];
+        let (url, guard) = balance.select(|_| true, &HeaderMap::new(), &client());
+        assert_eq!(url.as_str(), "http://b/");
+        assert_eq!(balance.outstanding(), vec![2, 1, 1]);
+        drop(guard);
+        assert_eq!(balance.outstanding(), vec![2, 0, 1]);
+
+        // Ties rotate between members
+        *balance.state.outstanding.lock().unwrap() = vec![0, 0, 0];
+        let picks: Vec<String> = (0..3).map(|_| pick(&balance, &HeaderMap::new())).collect();
+        assert!(picks.contains(&"http://a/".to_string()));
+        assert!(picks.contains(&"http://b/".to_string()));
+        assert!(picks.contains(&"http://c/".to_string()));
+    }
+
+    #[test]
+    fn unhealthy_members_skipped_unless_all_are() {
+        let balance = balance(
+            Strategy::RoundRobin,
+            &[("http://a:80", 1), ("http://b:80", 1)],
+        );
+        for _ in 0..4 {
+            let (url, _) =
+                balance.select(|u| u.host_str() == Some("b"), &HeaderMap::new(), &client());
+            assert_eq!(url.as_str(), "http://b/");
+        }
+        let picks: Vec<String> = (0..2)
+            .map(|_| {
+                balance
+                    .select(|_| false, &HeaderMap::new(), &client())
+                    .0
+                    .to_string()
+            })
+            .collect();
+        assert_ne!(picks[0], picks[1]);
+    }
+}
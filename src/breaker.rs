@@ -0,0 +1,414 @@
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::config::ConfigFile;
+use crate::error::Error as ProximaError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct CircuitBreaker {
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "default_error_rate")]
+    pub error_rate: u32,
+    #[serde(default = "default_window")]
+    pub window: u64,
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+// Open when half of the requests in the window fail
+fn default_error_rate() -> u32 {
+    50
+}
+
+// Error rate is measured over the last 30 seconds
+fn default_window() -> u64 {
+    30
+}
+
+// Too few requests in the window don't give a meaningful error rate
+fn default_min_requests() -> u32 {
+    10
+}
+
+// Members are ejected for 30 seconds before being probed
+fn default_cooldown() -> u64 {
+    30
+}
+
+impl CircuitBreaker {
+    // Errors and 5xx responses count as failures
+    pub fn failed(result: &Result<Response<Body>, ProximaError>) -> bool {
+        match result {
+            Ok(response) => response.status().is_server_error(),
+            Err(_) => true,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    changed: Instant,
+    consecutive_failures: u32,
+    outcomes: VecDeque<(Instant, bool)>,
+    ejections: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MemberCircuit {
+    pub member: String,
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub requests: usize,
+    pub failures: usize,
+    pub ejections: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BreakerRegistry {
+    circuits: Arc<RwLock<HashMap<String, Circuit>>>,
+}
+
+impl BreakerRegistry {
+    // Open circuits become available again once the cooldown has passed, letting a request
+    // through as a probe. A half open circuit waits on that probe, unless it has been
+    // outstanding for a whole cooldown.
+    pub fn available(&self, breaker: &Option<CircuitBreaker>, member: &Url) -> bool {
+        let breaker = match breaker {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        match self.circuits.read().unwrap().get(member.as_str()) {
+            Some(circuit) => match circuit.state {
+                CircuitState::Closed => true,
+                CircuitState::Open | CircuitState::HalfOpen => {
+                    circuit.changed.elapsed() >= Duration::from_secs(breaker.cooldown)
+                }
+            },
+            None => true,
+        }
+    }
+
+    // Check a member's circuit before sending it a request. An open circuit becomes half open
+    // once the cooldown has passed, and only that request is let through as a probe, until it
+    // completes or has been outstanding for a whole cooldown. The check and the transition
+    // happen under one lock, so concurrent requests can't send more than one probe.
+    pub fn try_begin(&self, breaker: &Option<CircuitBreaker>, member: &Url) -> bool {
+        let breaker = match breaker {
+            Some(breaker) => breaker,
+            None => return true,
+        };
+        let mut circuits = self.circuits.write().unwrap();
+        let circuit = match circuits.get_mut(member.as_str()) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+        if circuit.state == CircuitState::Closed {
+            return true;
+        }
+        if circuit.changed.elapsed() < Duration::from_secs(breaker.cooldown) {
+            return false;
+        }
+        log::info!("\"Circuit for {} is half open, sending probe\"", member);
+        circuit.transition(CircuitState::HalfOpen, member);
+        true
+    }
+
+    pub fn record(&self, breaker: &Option<CircuitBreaker>, member: &Url, failed: bool) {
+        let breaker = match breaker {
+            Some(breaker) => breaker,
+            None => return,
+        };
+
+        let mut circuits = self.circuits.write().unwrap();
+        let circuit = circuits
+            .entry(member.to_string())
+            .or_insert_with(|| Circuit {
+                state: CircuitState::Closed,
+                changed: Instant::now(),
+                consecutive_failures: 0,
+                outcomes: VecDeque::new(),
+                ejections: 0,
+            });
+
+        let now = Instant::now();
+        let window = Duration::from_secs(breaker.window);
+        circuit.outcomes.push_back((now, failed));
+        while let Some((at, _)) = circuit.outcomes.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            circuit.outcomes.pop_front();
+        }
+        circuit.consecutive_failures = match failed {
+            true => circuit.consecutive_failures + 1,
+            false => 0,
+        };
+
+        match (circuit.state, failed) {
+            (CircuitState::Closed, false) | (CircuitState::Open, _) => (),
+            (CircuitState::HalfOpen, false) => {
+                log::info!("\"Circuit for {} is closed\"", member);
+                circuit.outcomes.clear();
+                circuit.transition(CircuitState::Closed, member);
+            }
+            (CircuitState::HalfOpen, true) => {
+                log::warn!("\"Probe to {} failed, circuit is open\"", member);
+                circuit.eject(member);
+            }
+            (CircuitState::Closed, true) => {
+                let requests = circuit.outcomes.len();
+                let failures = circuit.failures();
+                if circuit.consecutive_failures >= breaker.consecutive_failures {
+                    log::warn!(
+                        "\"Circuit for {} is open after {} consecutive failures\"",
+                        member,
+                        circuit.consecutive_failures
+                    );
+                    circuit.eject(member);
+                } else if requests >= breaker.min_requests as usize
+                    && failures * 100 >= requests * breaker.error_rate as usize
+                {
+                    log::warn!(
+                        "\"Circuit for {} is open after {} of {} requests failed\"",
+                        member,
+                        failures,
+                        requests
+                    );
+                    circuit.eject(member);
+                }
+            }
+        }
+    }
+
+    pub fn members(&self, config_file: &ConfigFile) -> Vec<MemberCircuit> {
+        let cooldowns = breakers(config_file);
+        let mut members: Vec<MemberCircuit> = self
+            .circuits
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(member, circuit)| {
+                let retry_in = match (circuit.state, cooldowns.get(member)) {
+                    (CircuitState::Open, Some(breaker)) => Some(
+                        breaker
+                            .cooldown
+                            .saturating_sub(circuit.changed.elapsed().as_secs()),
+                    ),
+                    _ => None,
+                };
+                MemberCircuit {
+                    member: member.clone(),
+                    state: circuit.state,
+                    consecutive_failures: circuit.consecutive_failures,
+                    requests: circuit.outcomes.len(),
+                    failures: circuit.failures(),
+                    ejections: circuit.ejections,
+                    retry_in,
+                }
+            })
+            .collect();
+        members.sort_by(|a, b| a.member.cmp(&b.member));
+        members
+    }
+
+    // Forget members which no longer have a circuit breaker in the config
+    pub fn sync(&self, config_file: &ConfigFile) {
+        let members: HashSet<String> = breakers(config_file).into_keys().collect();
+        self.circuits.write().unwrap().retain(|member, _| {
+            let keep = members.contains(member);
+            if !keep {
+                metrics::gauge!("proxima_upstream_circuit_state", 0.0, "member" => member.clone());
+            }
+            keep
+        });
+    }
+}
+
+impl Circuit {
+    fn failures(&self) -> usize {
+        self.outcomes.iter().filter(|(_, failed)| *failed).count()
+    }
+
+    fn eject(&mut self, member: &Url) {
+        self.ejections += 1;
+        metrics::increment_counter!("proxima_upstream_ejections_total", "member" => member.to_string());
+        self.transition(CircuitState::Open, member);
+    }
+
+    fn transition(&mut self, state: CircuitState, member: &Url) {
+        self.state = state;
+        self.changed = Instant::now();
+        let value = match state {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        };
+        metrics::gauge!("proxima_upstream_circuit_state", value, "member" => member.to_string());
+    }
+}
+
+// Every member with a circuit breaker, along with its settings
+fn breakers(config_file: &ConfigFile) -> HashMap<String, CircuitBreaker> {
+    let mut members = HashMap::new();
+    for proxy in config_file.proxies() {
+        if let Some(breaker) = &proxy.circuit_breaker {
+            for member in proxy.url.members() {
+                members.insert(member.to_string(), breaker.clone());
+            }
+        }
+    }
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> Option<CircuitBreaker> {
+        Some(CircuitBreaker {
+            consecutive_failures: 3,
+            error_rate: 50,
+            window: 30,
+            min_requests: 4,
+            cooldown: 30,
+        })
+    }
+
+    fn member() -> Url {
+        Url::parse("http://api-0.api.svc:8080").unwrap()
+    }
+
+    fn state(registry: &BreakerRegistry) -> CircuitState {
+        registry.circuits.read().unwrap()[member().as_str()].state
+    }
+
+    // Move the circuit's last change back past the cooldown
+    fn cool_down(registry: &BreakerRegistry) {
+        let mut circuits = registry.circuits.write().unwrap();
+        let circuit = circuits.get_mut(member().as_str()).unwrap();
+        circuit.changed = Instant::now() - Duration::from_secs(31);
+    }
+
+    fn open(registry: &BreakerRegistry) {
+        for _ in 0..3 {
+            assert!(registry.try_begin(&breaker(), &member()));
+            registry.record(&breaker(), &member(), true);
+        }
+        assert_eq!(state(registry), CircuitState::Open);
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        // Too few requests for the error rate to count
+        let breaker = Some(CircuitBreaker {
+            min_requests: 100,
+            ..breaker().unwrap()
+        });
+        let registry = BreakerRegistry::default();
+        for failed in [true, true, false, true, true] {
+            registry.record(&breaker, &member(), failed);
+        }
+        assert_eq!(state(&registry), CircuitState::Closed);
+
+        registry.record(&breaker, &member(), true);
+        assert_eq!(state(&registry), CircuitState::Open);
+        assert!(!registry.available(&breaker, &member()));
+        assert!(!registry.try_begin(&breaker, &member()));
+    }
+
+    #[test]
+    fn opens_on_error_rate() {
+        let registry = BreakerRegistry::default();
+        for failed in [false, true, false] {
+            registry.record(&breaker(), &member(), failed);
+        }
+        assert_eq!(state(&registry), CircuitState::Closed);
+
+        registry.record(&breaker(), &member(), true);
+        assert_eq!(state(&registry), CircuitState::Open);
+    }
+
+    #[test]
+    fn sends_one_probe_at_a_time() {
+        let registry = BreakerRegistry::default();
+        open(&registry);
+        cool_down(&registry);
+        assert!(registry.available(&breaker(), &member()));
+
+        assert!(registry.try_begin(&breaker(), &member()));
+        assert_eq!(state(&registry), CircuitState::HalfOpen);
+        assert!(!registry.available(&breaker(), &member()));
+        assert!(!registry.try_begin(&breaker(), &member()));
+
+        // A probe outstanding for a whole cooldown is replaced
+        cool_down(&registry);
+        assert!(registry.try_begin(&breaker(), &member()));
+        assert!(!registry.try_begin(&breaker(), &member()));
+    }
+
+    #[test]
+    fn probe_success_closes() {
+        let registry = BreakerRegistry::default();
+        open(&registry);
+        cool_down(&registry);
+        assert!(registry.try_begin(&breaker(), &member()));
+        registry.record(&breaker(), &member(), false);
+
+        assert_eq!(state(&registry), CircuitState::Closed);
+        assert!(registry.try_begin(&breaker(), &member()));
+        assert!(registry.try_begin(&breaker(), &member()));
+
+        // The window starts over, so earlier failures don't reopen the circuit
+        registry.record(&breaker(), &member(), true);
+        assert_eq!(state(&registry), CircuitState::Closed);
+    }
+
+    #[test]
+    fn probe_failure_reopens() {
+        let registry = BreakerRegistry::default();
+        open(&registry);
+        cool_down(&registry);
+        assert!(registry.try_begin(&breaker(), &member()));
+        registry.record(&breaker(), &member(), true);
+
+        assert_eq!(state(&registry), CircuitState::Open);
+        assert!(!registry.try_begin(&breaker(), &member()));
+        assert_eq!(
+            registry.circuits.read().unwrap()[member().as_str()].ejections,
+            2
+        );
+    }
+
+    #[test]
+    fn without_breaker_is_always_available() {
+        let registry = BreakerRegistry::default();
+        for _ in 0..10 {
+            registry.record(&None, &member(), true);
+        }
+        assert!(registry.circuits.read().unwrap().is_empty());
+        assert!(registry.try_begin(&None, &member()));
+    }
+}
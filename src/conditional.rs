@@ -0,0 +1,283 @@
+use hyper::header::COOKIE;
+use hyper::{HeaderMap, Method};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::config::Proxy;
+use crate::error::Error as ProximaError;
+use crate::security::Security;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Conditional {
+    pub rules: Vec<ConditionalRule>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<Proxy>,
+    // Used by rules and the default when they have no security of their own, as clients
+    // choose the rule through the request they send
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security: Option<Security>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionalRule {
+    pub when: Conditions,
+    pub proxy: Proxy,
+}
+
+// All specified conditions must match for a rule to be selected
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct Conditions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub methods: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cookies: Option<BTreeMap<String, String>>,
+}
+
+impl Conditional {
+    // Return the proxy for the first matching rule, or the default proxy
+    pub fn select(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        query: Option<&str>,
+    ) -> Result<Proxy, ProximaError> {
+        let queries: Vec<(String, String)> = match query {
+            Some(q) => serde_urlencoded::from_str(q).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let cookies = Conditions::cookies(headers);
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.when.matches(method, headers, &queries, &cookies) {
+                log::debug!("\"Request matched conditional rule {}\"", index);
+                return Ok(self.secured(&rule.proxy));
+            }
+        }
+
+        match &self.default {
+            Some(proxy) => {
+                log::debug!("\"No conditional rule matched, using default\"");
+                Ok(self.secured(proxy))
+            }
+            None => {
+                log::debug!("\"No conditional rule matched, and no default is set\"");
+                Err(ProximaError::UnknownProxy)
+            }
+        }
+    }
+
+    // The rules and default, with the security each is checked against
+    pub fn proxies(&self) -> impl Iterator<Item = Proxy> + '_ {
+        self.rules
+            .iter()
+            .map(|rule| &rule.proxy)
+            .chain(&self.default)
+            .map(|proxy| self.secured(proxy))
+    }
+
+    fn secured(&self, proxy: &Proxy) -> Proxy {
+        let mut proxy = proxy.clone();
+        if proxy.security.is_none() {
+            proxy.security = self.security.clone();
+        }
+        proxy
+    }
+}
+
+impl Conditions {
+    pub fn cookies(headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    pub fn matches(
+        &self,
+        method: &Method,
+        headers: &HeaderMap,
+        queries: &[(String, String)],
+        cookies: &[(String, String)],
+    ) -> bool {
+        if let Some(ref methods) = self.methods {
+            if !methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+            {
+                return false;
+            }
+        }
+
+        if let Some(ref required) = self.headers {
+            for (name, value) in required {
+                let matched = headers
+                    .get_all(name.as_str())
+                    .iter()
+                    .any(|h| h.to_str().map(|h| h == value).unwrap_or(false));
+                if !matched {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(ref required) = self.query {
+            for (name, value) in required {
+                if !queries.iter().any(|(k, v)| k == name && v == value) {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(ref required) = self.cookies {
+            for (name, value) in required {
+                if !cookies.iter().any(|(k, v)| k == name && v == value) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for Conditional {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "conditional={} rules", self.rules.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONDITIONAL: &str = "
+rules:
+- when:
+    methods: [post, put]
+    headers:
+      x-canary: 'true'
+  proxy:
+    url: http://canary:8080
+- when:
+    query:
+      version: '2'
+  proxy:
+    url: http://v2:8080
+    security:
+      whitelist:
+        networks: [10.0.0.0/8]
+- when:
+    cookies:
+      beta: enabled
+  proxy:
+    url: http://beta:8080
+default:
+  url: http://stable:8080
+security:
+  whitelist:
+    networks: [192.168.0.0/16]
+";
+
+    fn conditional() -> Conditional {
+        serde_yaml::from_str(CONDITIONAL).unwrap()
+    }
+
+    fn select(method: Method, headers: &[(&'static str, &str)], query: Option<&str>) -> String {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        conditional()
+            .select(&method, &map, query)
+            .unwrap()
+            .url
+            .to_string()
+    }
+
+    #[test]
+    fn rules_match_all_conditions() {
+        let canary = [("x-canary", "true")];
+        assert_eq!(select(Method::POST, &canary, None), "http://canary:8080/");
+        assert_eq!(select(Method::GET, &canary, None), "http://stable:8080/");
+        assert_eq!(select(Method::PUT, &[], None), "http://stable:8080/");
+        assert_eq!(
+            select(Method::GET, &[], Some("a=1&version=2")),
+            "http://v2:8080/"
+        );
+        assert_eq!(
+            select(Method::GET, &[], Some("version=20")),
+            "http://stable:8080/"
+        );
+        let cookies = [("cookie", "theme=dark"), ("cookie", "beta=enabled")];
+        assert_eq!(select(Method::GET, &cookies, None), "http://beta:8080/");
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        assert_eq!(
+            select(
+                Method::POST,
+                &[("x-canary", "true"), ("cookie", "beta=enabled")],
+                Some("version=2")
+            ),
+            "http://canary:8080/"
+        );
+    }
+
+    #[test]
+    fn no_match_without_default() {
+        let mut conditional = conditional();
+        conditional.default = None;
+        assert!(matches!(
+            conditional.select(&Method::GET, &HeaderMap::new(), None),
+            Err(ProximaError::UnknownProxy)
+        ));
+    }
+
+    #[test]
+    fn rules_inherit_security() {
+        let networks: Vec<String> = conditional()
+            .proxies()
+            .map(|proxy| {
+                let whitelist = proxy.security.unwrap().whitelist.unwrap();
+                whitelist.networks.unwrap()[0].to_string()
+            })
+            .collect();
+        assert_eq!(
+            networks,
+            [
+                "192.168.0.0/16",
+                "10.0.0.0/8",
+                "192.168.0.0/16",
+                "192.168.0.0/16"
+            ]
+        );
+    }
+
+    #[test]
+    fn cookies_are_split() {
+        let mut headers = HeaderMap::new();
+        headers.append(COOKIE, "a=1; b=2=3".parse().unwrap());
+        headers.append(COOKIE, "invalid; c=".parse().unwrap());
+        assert_eq!(
+            Conditions::cookies(&headers),
+            [
+                ("a".to_string(), "1".to_string()),
+                ("b".to_string(), "2=3".to_string()),
+                ("c".to_string(), "".to_string())
+            ]
+        );
+    }
+}
@@ -2,17 +2,21 @@ use async_recursion::async_recursion;
 use axum::http::Request;
 use chrono::Utc;
 use clap::{crate_description, crate_name, crate_version};
-use hyper::header::{HeaderName, HeaderValue};
+use hyper::header::{
+    HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
 use hyper::HeaderMap;
 use hyper::{Body, Uri};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::fmt;
-use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::sync::RwLock;
@@ -20,15 +24,27 @@ use url::Url;
 use vault_client_rs::client::Client as VaultClient;
 
 use crate::auth::server::ServerAuth;
+use crate::breaker::{BreakerRegistry, CircuitBreaker, MemberCircuit};
 use crate::cache::Cache;
+use crate::conditional::Conditional;
 use crate::config_global::GlobalConfig;
+use crate::config_include::{ConfigFormat, ConfigLoader, ConfigSource};
 use crate::error::Error as ProximaError;
+use crate::forwarded::{self, ForwardedHeaders};
+use crate::header_rules::HeaderRules;
+use crate::health::HealthRegistry;
+use crate::history::{display_hash, ConfigVersion, DiffParams, History, VersionSummary};
 use crate::https::ClientBuilder;
-use crate::https::HttpsClient;
+use crate::https::{HttpsClient, Protocol};
 use crate::path::ProxyPath;
+use crate::retry::RetryPolicy;
+use crate::rewrite::Rewrite;
+use crate::route_edit::{self, RouteChange, RouteEdit};
 use crate::security::EndpointSecurity;
 use crate::security::{display_security, Security};
+use crate::tls::TlsTermination;
 use crate::urls::Urls;
+use crate::validate::Report;
 use crate::vault::Vault;
 
 type BoxResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
@@ -39,6 +55,34 @@ pub struct ConfigFile {
     #[serde(default)]
     pub global: GlobalConfig,
     pub routes: ConfigMap,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, VirtualHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualHost {
+    pub routes: ConfigMap,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<Security>,
+}
+
+// The last config fetched from a remote url, along with the validators needed for
+// conditional requests
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+    pub config_file: ConfigFile,
+    pub source: ConfigSource,
+}
+
+// Each virtual host gets its own cache and mappings, as both are keyed by path
+#[derive(Debug, Clone, Default)]
+pub struct HostCache {
+    pub cache: Cache<Endpoint>,
+    pub mappings: Cache<String>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -51,6 +95,21 @@ pub struct Config {
     pub hash: Arc<RwLock<u64>>,
     pub cache: Cache<Endpoint>,
     pub mappings: Cache<String>,
+    pub regexes: Cache<Regex>,
+    pub host_caches: Arc<RwLock<HashMap<String, HostCache>>>,
+    pub remote_configs: Arc<RwLock<HashMap<String, RemoteConfig>>>,
+    pub snapshot: Option<String>,
+    pub serving_snapshot: Arc<RwLock<bool>>,
+    pub overlay: Arc<RwLock<Vec<RouteChange>>>,
+    pub source: Arc<RwLock<Option<ConfigSource>>>,
+    // Held while the config is read, changed and swapped back in, so reloads and api
+    // changes can't overwrite each other
+    pub edit_lock: Arc<tokio::sync::Mutex<()>>,
+    pub history: Arc<RwLock<History>>,
+    pub pinned: Arc<RwLock<Option<u64>>>,
+    pub health: HealthRegistry,
+    pub breakers: BreakerRegistry,
+    pub tls: TlsTermination,
     pub https_client: HttpsClient,
     pub vault_client: Option<VaultClient>,
 }
@@ -96,6 +155,16 @@ pub struct Proxy {
     pub security: Option<Security>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub config: Option<ProxyConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<Rewrite>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_headers: Option<HeaderRules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<HeaderRules>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
@@ -103,6 +172,13 @@ pub struct Proxy {
 pub struct ProxyConfig {
     #[serde(default)]
     pub preserve_host_header: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub forwarded_headers: ForwardedHeaders,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
@@ -125,6 +201,8 @@ pub enum Endpoint {
     Static(Static),
     #[allow(non_camel_case_types)]
     Redirect(Redirect),
+    #[allow(non_camel_case_types)]
+    Conditional(Conditional),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
@@ -191,6 +269,7 @@ impl fmt::Display for Endpoint {
             Endpoint::HttpConfig(p) => write!(f, "{}", p),
             Endpoint::Vault(p) => write!(f, "{}", p),
             Endpoint::Redirect(p) => write!(f, "{}", p),
+            Endpoint::Conditional(p) => write!(f, "{}", p),
         }
     }
 }
@@ -225,12 +304,61 @@ impl fmt::Display for Static {
     }
 }
 
+impl ConfigFile {
+    // Find the virtual host matching the request host, preferring exact matches,
+    // followed by the longest matching *.domain wildcard
+    pub fn virtual_host(&self, host: Option<&str>) -> Option<(&String, &VirtualHost)> {
+        let host = host?;
+        if let Some(exact) = self.hosts.get_key_value(host) {
+            return Some(exact);
+        }
+
+        self.hosts
+            .iter()
+            .filter(|(pattern, _)| match pattern.strip_prefix('*') {
+                Some(domain) if domain.starts_with('.') => {
+                    host.len() > domain.len() && host.ends_with(domain)
+                }
+                _ => false,
+            })
+            .max_by_key(|(pattern, _)| pattern.len())
+    }
+
+    // Every proxy endpoint, including conditional rules and virtual hosts
+    pub fn proxies(&self) -> Vec<&Proxy> {
+        let mut proxies = Vec::new();
+        collect_proxies(&self.routes, &mut proxies);
+        for vhost in self.hosts.values() {
+            collect_proxies(&vhost.routes, &mut proxies);
+        }
+        proxies
+    }
+}
+
+fn collect_proxies<'a>(routes: &'a ConfigMap, proxies: &mut Vec<&'a Proxy>) {
+    for route in routes.values() {
+        match route {
+            Route::ConfigMap(map) => collect_proxies(map, proxies),
+            Route::Endpoint(Endpoint::Proxy(proxy)) => proxies.push(proxy),
+            Route::Endpoint(Endpoint::Conditional(conditional)) => {
+                proxies.extend(conditional.rules.iter().map(|rule| &rule.proxy));
+                proxies.extend(conditional.default.iter());
+            }
+            Route::Endpoint(_) => (),
+        }
+    }
+}
+
 impl Headers {
-    pub fn insert_headers(&self, map: &mut HeaderMap) -> Result<(), ProximaError> {
+    pub fn insert_headers(
+        &self,
+        map: &mut HeaderMap,
+        path: &ProxyPath,
+    ) -> Result<(), ProximaError> {
         for header in &self.0 {
             log::debug!("Inserting {} into map", header.name);
             let name = HeaderName::from_lowercase(header.name.to_lowercase().as_bytes())?;
-            let value = HeaderValue::from_str(&header.value)?;
+            let value = HeaderValue::from_str(&path.interpolate(&header.value))?;
             map.insert(name, value);
         }
         Ok(())
@@ -242,6 +370,11 @@ impl Config {
         self.config_file.read().await.clone()
     }
 
+    pub async fn circuits(&self) -> Vec<MemberCircuit> {
+        let config_file = self.config_file.read().await;
+        self.breakers.members(&config_file)
+    }
+
     pub async fn get_cache(&self) -> Map<String, Value> {
         self.cache.cache().await
     }
@@ -252,6 +385,59 @@ impl Config {
 
     pub async fn clear_cache(&mut self) {
         self.cache.clear().await;
+        self.host_caches.write().await.clear();
+    }
+
+    // Returns the client auth for the host, falling back to the global auth
+    pub async fn auth(&self, host: Option<&str>) -> Option<Security> {
+        let config_file = self.config_file.read().await;
+        match config_file.virtual_host(host) {
+            Some((_, vhost)) if vhost.auth.is_some() => vhost.auth.clone(),
+            _ => config_file.global.security.auth.clone(),
+        }
+    }
+
+    // Security required by api endpoints which change the config
+    pub async fn api_security(&self) -> Option<Security> {
+        let config_file = self.config_file.read().await;
+        config_file.global.security.api.clone()
+    }
+
+    // Resolve the client address of a request, believing forwarding headers from trusted proxies.
+    // None when a trusted proxy's forwarding chain doesn't name an untrusted client.
+    pub async fn client_addr(&self, headers: &HeaderMap, peer: SocketAddr) -> Option<SocketAddr> {
+        let config_file = self.config_file.read().await;
+        let network = &config_file.global.network;
+        forwarded::client_addr(
+            headers,
+            peer,
+            &network.trusted_proxies,
+            network.client_ip_header,
+        )
+    }
+
+    pub async fn trusts(&self, ip: IpAddr) -> bool {
+        let config_file = self.config_file.read().await;
+        forwarded::is_trusted(&config_file.global.network.trusted_proxies, ip)
+    }
+
+    // Get a copy of this config which caches into the virtual host's own cache and mappings
+    pub async fn host_view(&self, pattern: &str) -> Config {
+        let host_cache = {
+            let mut host_caches = self.host_caches.write().await;
+            host_caches
+                .entry(pattern.to_string())
+                .or_insert_with(|| HostCache {
+                    cache: Cache::new(Some(format!("cache:{}", pattern))),
+                    mappings: Cache::new(Some(format!("mappings:{}", pattern))),
+                })
+                .clone()
+        };
+
+        let mut view = self.clone();
+        view.cache = host_cache.cache;
+        view.mappings = host_cache.mappings;
+        view
     }
 
     pub fn vault_client(&self) -> Result<VaultClient, ProximaError> {
@@ -262,9 +448,15 @@ impl Config {
     }
 
     pub async fn renew(&self) {
+        // Local config files are reloaded by the file watcher
+        if url::Url::parse(&self.location).is_err() {
+            return;
+        }
+
+        let interval = self.config_file.read().await.global.reload.interval.value();
         let last_read = self.last_read.read().await;
         let diff = Utc::now().timestamp() - *last_read;
-        if diff >= 30 {
+        if diff >= interval {
             // Only allow one config refresh to be active at once
             if let Ok(()) = self.refresh_lock.acquire() {
                 log::debug!("\"Cache has expired, kicking off config reload\"");
@@ -314,6 +506,7 @@ impl Config {
             config_file: Arc::new(RwLock::new(ConfigFile {
                 global: GlobalConfig::default(),
                 routes: BTreeMap::new(),
+                hosts: BTreeMap::new(),
             })),
             location: location.to_string(),
             config_authentication,
@@ -322,6 +515,19 @@ impl Config {
             hash: Arc::new(RwLock::new(u64::default())),
             cache: Cache::new(Some("cache".to_string())),
             mappings: Cache::new(Some("mappings".to_string())),
+            regexes: Cache::new(Some("regexes".to_string())),
+            host_caches: Arc::new(RwLock::new(HashMap::new())),
+            remote_configs: Arc::new(RwLock::new(HashMap::new())),
+            snapshot: None,
+            serving_snapshot: Arc::new(RwLock::new(false)),
+            overlay: Arc::new(RwLock::new(Vec::new())),
+            source: Arc::new(RwLock::new(None)),
+            edit_lock: Arc::new(tokio::sync::Mutex::new(())),
+            history: Arc::new(RwLock::new(History::default())),
+            pinned: Arc::new(RwLock::new(None)),
+            health: HealthRegistry::default(),
+            breakers: BreakerRegistry::default(),
+            tls: TlsTermination::default(),
             https_client,
             vault_client,
         }
@@ -330,6 +536,7 @@ impl Config {
     pub async fn cache_get(
         &mut self,
         mut path: ProxyPath,
+        routes: ConfigMap,
     ) -> Result<(Route, ProxyPath), ProximaError> {
         let path_str = path.path();
         log::debug!("\"Starting cache_get for {}\"", &path_str);
@@ -348,10 +555,7 @@ impl Config {
         // If path is just root, if there is no / root set, return default
         // This needs to be checked here, before we enter into recursion
         if path_str == "/" {
-            match self
-                .fetch(path.clone(), self.config_file().await.routes)
-                .await
-            {
+            match self.fetch(path.clone(), routes).await {
                 Ok((r, v)) => return Ok((r, v)),
                 _ => {
                     let body = json!({ "version": crate_version!(), "name": crate_name!(), "description": crate_description!()}).to_string();
@@ -365,9 +569,14 @@ impl Config {
             }
         }
 
-        let result = self.fetch(path, self.config_file().await.routes).await?;
+        let result = self.fetch(path, routes).await?;
 
         if let (Route::Endpoint(_), ref path) = result {
+            // Paths with captured params are never cached, so skip the mapping
+            if !path.params().is_empty() {
+                return Ok(result);
+            }
+
             // Set cache and mappings
             log::debug!("Adding {} to mappings", path.path());
             self.mappings
@@ -406,9 +615,20 @@ impl Config {
             }
         };
 
-        // If endpoint is not found in cache, check configmap
+        // If endpoint is not found in cache, check configmap, falling back to pattern keys
         let key = path.current();
-        match config.get(&key) {
+        let route = match config.get(&key) {
+            Some(route) => Some(route),
+            None => match Config::pattern_route(&mut path, &config) {
+                Some(route) => Some(route),
+                None => self.regex_route(&mut path, &config).await,
+            },
+        };
+
+        // Only cache endpoints matched by a literal key, as captured params are not cached
+        let cacheable = path.params().is_empty() && config.contains_key(&key);
+
+        match route {
             Some(Route::ConfigMap(entry)) => {
                 log::debug!(
                     "Found ConfigMap at {}",
@@ -450,7 +670,7 @@ impl Config {
                         // self.cache.set(&key, &wrapper).await;
 
                         // Get the http config as config_file
-                        let config_file = match self
+                        let (config_file, _) = match self
                             .parse(Some(entry.url.clone()), entry.authentication.clone())
                             .await
                         {
@@ -501,7 +721,9 @@ impl Config {
 
                                 // If vault secret is Endpoint variant, cache endpoint
                                 if let Route::Endpoint(ref endpoint) = route {
-                                    self.cache.set(&path.key().expect("odd"), endpoint).await;
+                                    if cacheable {
+                                        self.cache.set(&path.key().expect("odd"), endpoint).await;
+                                    }
                                 };
 
                                 Ok((route, path))
@@ -554,12 +776,14 @@ impl Config {
                             &path.key().unwrap_or_else(|| "None".to_string())
                         );
 
-                        // Save entry into cache
-                        let key = &path.key().expect("weird");
-                        log::debug!("Adding {} to cache", key);
-
                         let wrapper = Endpoint::Proxy(entry.clone());
-                        self.cache.set(key, &wrapper).await;
+
+                        // Save entry into cache
+                        if cacheable {
+                            let key = &path.key().expect("weird");
+                            log::debug!("Adding {} to cache", key);
+                            self.cache.set(key, &wrapper).await;
+                        }
 
                         // Return endpoint
                         Ok((Route::Endpoint(wrapper), path))
@@ -570,8 +794,10 @@ impl Config {
                             &path.key().unwrap_or_else(|| "None".to_string())
                         );
 
-                        let wrapper = Endpoint::Static(entry.clone());
-                        self.cache.set(&key, &wrapper).await;
+                        if cacheable {
+                            let wrapper = Endpoint::Static(entry.clone());
+                            self.cache.set(&key, &wrapper).await;
+                        }
 
                         // Return endpoint
                         Ok((Route::Endpoint(Endpoint::Static(entry.clone())), path))
@@ -582,21 +808,109 @@ impl Config {
                             &path.key().unwrap_or_else(|| "None".to_string())
                         );
 
-                        let wrapper = Endpoint::Redirect(entry.clone());
-                        self.cache.set(&key, &wrapper).await;
+                        if cacheable {
+                            let wrapper = Endpoint::Redirect(entry.clone());
+                            self.cache.set(&key, &wrapper).await;
+                        }
 
                         // Return endpoint
                         Ok((Route::Endpoint(Endpoint::Redirect(entry.clone())), path))
                     }
+                    Endpoint::Conditional(entry) => {
+                        log::debug!(
+                            "Found Conditional at {}",
+                            &path.key().unwrap_or_else(|| "None".to_string())
+                        );
+
+                        let wrapper = Endpoint::Conditional(entry.clone());
+
+                        // Save entry into cache
+                        if cacheable {
+                            let key = &path.key().expect("weird");
+                            log::debug!("Adding {} to cache", key);
+                            self.cache.set(key, &wrapper).await;
+                        }
+
+                        // Return endpoint
+                        Ok((Route::Endpoint(wrapper), path))
+                    }
                 }
             }
             None => Err(ProximaError::UnknownProxy),
         }
     }
 
-    pub async fn get(&mut self, path: ProxyPath) -> Result<(Route, ProxyPath), ProximaError> {
+    // Find the most specific pattern key matching the path, preferring keys spanning
+    // more segments, followed by keys with more literal segments
+    fn pattern_route<'a>(path: &mut ProxyPath, config: &'a ConfigMap) -> Option<&'a Route> {
+        let mut patterns: Vec<(&String, &Route)> = config
+            .iter()
+            .filter(|(k, _)| ProxyPath::is_pattern(k))
+            .collect();
+
+        patterns.sort_by_key(|(k, _)| {
+            let segments = k.split('/').count();
+            let literals = k
+                .split('/')
+                .filter(|s| *s != "*" && !s.starts_with('{'))
+                .count();
+            std::cmp::Reverse((segments, literals))
+        });
+
+        patterns
+            .into_iter()
+            .find(|(k, _)| path.capture(k))
+            .map(|(_, route)| route)
+    }
+
+    // Find the first regex key, in key order, matching the remainder of the path
+    async fn regex_route<'a>(
+        &mut self,
+        path: &mut ProxyPath,
+        config: &'a ConfigMap,
+    ) -> Option<&'a Route> {
+        for (key, route) in config.iter() {
+            let pattern = match ProxyPath::regex_key(key) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let regex = match self.regexes.get(pattern).await {
+                Some(r) => r,
+                None => match ProxyPath::route_regex(pattern) {
+                    Ok(r) => {
+                        self.regexes.set(pattern, &r).await;
+                        r
+                    }
+                    Err(e) => {
+                        log::error!("\"Unable to compile route regex {}: {}\"", pattern, e);
+                        continue;
+                    }
+                },
+            };
+
+            if path.capture_regex(&regex) {
+                return Some(route);
+            }
+        }
+        None
+    }
+
+    pub async fn get(
+        &mut self,
+        path: ProxyPath,
+        host: Option<&str>,
+    ) -> Result<(Route, ProxyPath), ProximaError> {
         self.renew().await;
-        self.cache_get(path).await
+        let config_file = self.config_file().await;
+        match config_file.virtual_host(host) {
+            Some((pattern, vhost)) => {
+                log::debug!("\"Found virtual host {} for {:?}\"", pattern, host);
+                let mut view = self.host_view(pattern).await;
+                view.cache_get(path, vhost.routes.clone()).await
+            }
+            None => self.cache_get(path, config_file.routes).await,
+        }
     }
 
     pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
@@ -613,13 +927,279 @@ impl Config {
     }
 
     pub async fn update(&mut self) -> BoxResult<()> {
-        let new_config = self.parse(None, self.config_authentication.clone()).await?;
+        let edit_lock = self.edit_lock.clone();
+        let _edit = edit_lock.lock().await;
+        self.load().await
+    }
+
+    // Read and swap in the config source, with the edit lock held
+    async fn load(&mut self) -> BoxResult<()> {
+        let (mut new_config, source) = self.parse(None, self.config_authentication.clone()).await?;
+        *self.source.write().await = Some(source.clone());
+
+        // Reapply in-memory route changes made through the api
+        for change in self.overlay.read().await.iter() {
+            if let Err(e) =
+                route_edit::set(&mut new_config.routes, &change.keys, change.route.clone())
+            {
+                log::warn!(
+                    "\"Unable to reapply change to {}: {}\"",
+                    change.keys.join("/"),
+                    e
+                );
+            }
+        }
+
+        // Keep a rolled back config until the config source changes
+        let pinned = *self.pinned.read().await;
+        let changed = if pinned == Some(Config::calculate_hash(&new_config)) {
+            log::debug!("\"Config source unchanged since rollback, keeping rolled back config\"");
+            false
+        } else {
+            *self.pinned.write().await = None;
+            self.swap(new_config.clone(), "reload").await
+        };
+        *self.last_read.write().await = Utc::now().timestamp();
+
+        // Config source is reachable again, so stop serving from the snapshot
+        let mut serving_snapshot = self.serving_snapshot.write().await;
+        if *serving_snapshot {
+            log::info!("\"Config source is available again, no longer serving snapshot\"");
+        }
+        if changed || *serving_snapshot {
+            self.write_snapshot(&source);
+        }
+        *serving_snapshot = false;
+
+        Ok(())
+    }
+
+    pub async fn route(&self, path: &str) -> Option<Route> {
+        let config_file = self.config_file.read().await;
+        let keys = route_edit::resolve_path(&config_file.routes, path);
+        route_edit::get(&config_file.routes, &keys).cloned()
+    }
+
+    // Add, replace, patch or remove the route at path, either in memory, or by writing the
+    // change back to the config file
+    pub async fn edit_route(
+        &mut self,
+        path: &str,
+        edit: RouteEdit,
+        persist: bool,
+    ) -> Result<(), ProximaError> {
+        let edit_lock = self.edit_lock.clone();
+        let _edit = edit_lock.lock().await;
+
+        let mut config_file = self.config_file().await;
+        let keys = route_edit::resolve_path(&config_file.routes, path);
+
+        // Patches are merged into the route as written, rather than as serialized, which
+        // would leave out its credentials
+        let value = match &edit {
+            RouteEdit::Put(value) => Some(value.clone()),
+            RouteEdit::Patch(patch) => {
+                let mut value = self
+                    .route_value(&keys)
+                    .await?
+                    .ok_or(ProximaError::UnknownProxy)?;
+                route_edit::merge_patch(&mut value, patch.clone());
+                Some(value)
+            }
+            RouteEdit::Delete => None,
+        };
+        let route = value.as_ref().map(route_edit::parse).transpose()?;
+
+        if let (Some(route), Some((key, parents))) = (&route, keys.split_last()) {
+            let report = Report::route(&parents.join("/"), key, route).await;
+            for warning in &report.warnings {
+                log::warn!("\"{}\"", warning);
+            }
+            if !report.is_valid() {
+                let issues: Vec<String> = report.issues.iter().map(|i| i.to_string()).collect();
+                return Err(ProximaError::InvalidRoute(issues.join("; ")));
+            }
+        }
+
+        // Apply to a copy first, so conflicts are caught before anything is changed
+        route_edit::set(&mut config_file.routes, &keys, route.clone())?;
+
+        if persist {
+            let included = match &*self.source.read().await {
+                Some(source) => source.included_route(&keys)?,
+                None => None,
+            };
+            if let Some(path) = included {
+                return Err(ProximaError::IncludedRoute(
+                    keys.join("/"),
+                    path.display().to_string(),
+                ));
+            }
+
+            let previous = self.overlay.read().await.clone();
+            self.supersede_overlay(&keys, &value).await?;
+            let original = match route_edit::write_back(&self.location, &keys, &edit) {
+                Ok(original) => original,
+                Err(e) => {
+                    *self.overlay.write().await = previous;
+                    return Err(e);
+                }
+            };
+            if let Err(e) = self.load().await {
+                log::error!(
+                    "\"Reverting {} after failed reload: {}\"",
+                    &self.location,
+                    e
+                );
+                *self.overlay.write().await = previous;
+                std::fs::write(&self.location, original)?;
+                return Err(ProximaError::InvalidRoute(e.to_string()));
+            }
+        } else {
+            self.supersede_overlay(&keys, &value).await?;
+            let mut overlay = self.overlay.write().await;
+            overlay.push(RouteChange {
+                keys: keys.clone(),
+                route,
+                value,
+            });
+            drop(overlay);
+            self.swap(config_file, "api").await;
+        }
+
+        log::info!("\"Route {} changed through the api\"", keys.join("/"));
+        Ok(())
+    }
+
+    // Drop in-memory changes within the edited route, which the edit replaces, and carry the
+    // edit into changes to its parent folders, which would otherwise undo it when reapplied
+    async fn supersede_overlay(
+        &self,
+        keys: &[String],
+        value: &Option<Value>,
+    ) -> Result<(), ProximaError> {
+        let mut overlay = self.overlay.write().await;
+        overlay.retain(|c| !c.keys.starts_with(keys));
+        for change in overlay.iter_mut().filter(|c| keys.starts_with(&c.keys)) {
+            let mut folder = change
+                .value
+                .clone()
+                .unwrap_or_else(|| Value::Object(Default::default()));
+            route_edit::set_within(&mut folder, &keys[change.keys.len()..], value.clone())?;
+            change.route = Some(route_edit::parse(&folder)?);
+            change.value = Some(folder);
+        }
+        Ok(())
+    }
+
+    // The route at keys as last sent through the api, or as written in the config source
+    async fn route_value(&self, keys: &[String]) -> Result<Option<Value>, ProximaError> {
+        // Later changes replace earlier ones, including changes to parent folders
+        for change in self.overlay.read().await.iter().rev() {
+            if let Some(rest) = keys.strip_prefix(change.keys.as_slice()) {
+                let value = change.value.as_ref().and_then(|value| {
+                    rest.iter()
+                        .try_fold(value, |current, key| current.get(key.as_str()))
+                });
+                return Ok(value.cloned());
+            }
+        }
+
+        let route = match &*self.source.read().await {
+            Some(source) => source.route(keys)?,
+            None => None,
+        };
+        Ok(route.map(serde_json::to_value).transpose()?)
+    }
+
+    pub async fn history(&self) -> Vec<VersionSummary> {
+        self.history.read().await.summary()
+    }
+
+    pub async fn diff(&self, params: &DiffParams) -> Result<Value, ProximaError> {
+        self.history.read().await.diff(params)
+    }
+
+    // Swap a previous config back in. It is kept across reloads until the config source changes.
+    pub async fn rollback(&mut self, hash: &str) -> Result<(), ProximaError> {
+        let edit_lock = self.edit_lock.clone();
+        let _edit = edit_lock.lock().await;
+        let version = self.history.read().await.find(Some(hash), 0)?.clone();
+        let current_hash = *self.hash.read().await;
+        if version.hash == current_hash {
+            return Ok(());
+        }
+
+        log::warn!(
+            "\"Rolling back config from {} to {}\"",
+            display_hash(current_hash),
+            display_hash(version.hash)
+        );
+        *self.pinned.write().await = Some(current_hash);
+        self.swap(version.config_file, "rollback").await;
+        Ok(())
+    }
+
+    // Load the last known good config, for when the config source is unavailable
+    pub async fn load_snapshot(&mut self) -> Result<(), ProximaError> {
+        let snapshot = match &self.snapshot {
+            Some(s) => s,
+            None => return Err(ProximaError::NotFound),
+        };
+
+        let contents = std::fs::read(snapshot)?;
+        let source: ConfigSource = serde_json::from_slice(&contents)?;
+        let snapshot_config = source.parse()?;
+        *self.source.write().await = Some(source);
+        log::warn!("\"Serving config from snapshot {}\"", snapshot);
+
+        self.swap(snapshot_config, "snapshot").await;
+        *self.serving_snapshot.write().await = true;
+
+        // Leave last_read untouched, so the next request retries the config source
+        Ok(())
+    }
+
+    pub async fn serving_snapshot(&self) -> bool {
+        *self.serving_snapshot.read().await
+    }
+
+    // Write the snapshot to a temporary file first, so a crash can't leave a partial snapshot
+    fn write_snapshot(&self, source: &ConfigSource) {
+        let snapshot = match &self.snapshot {
+            Some(s) => s,
+            None => return,
+        };
+
+        let result = serde_json::to_vec_pretty(source)
+            .map_err(ProximaError::from)
+            .and_then(|contents| {
+                let temporary = format!("{}.tmp", snapshot);
+                let mut file = std::fs::File::create(&temporary)?;
+
+                // Snapshots hold the config source as written, credentials included
+                #[cfg(unix)]
+                file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+
+                std::io::Write::write_all(&mut file, &contents)?;
+                std::fs::rename(&temporary, snapshot)?;
+                Ok(())
+            });
+
+        match result {
+            Ok(()) => log::debug!("\"Wrote config snapshot to {}\"", snapshot),
+            Err(e) => log::error!("\"Unable to write config snapshot to {}: {}\"", snapshot, e),
+        }
+    }
+
+    // Swap in a new config if it differs from the current one, returning whether it changed
+    async fn swap(&mut self, new_config: ConfigFile, source: &str) -> bool {
         let current_config = self.config_file().await;
-        let now = Utc::now().timestamp();
         let new_config_hash = Config::calculate_hash(&new_config);
         let current_config_hash = Config::calculate_hash(&current_config);
+        let changed = current_config_hash != new_config_hash;
 
-        if current_config_hash != new_config_hash {
+        if changed {
             log::info!("\"Config has been updated\"");
             log::debug!(
                 "\"Config has been changed, new {} vs old {}\"",
@@ -631,27 +1211,42 @@ impl Config {
             let mut config_file = self.config_file.write().await;
             let mut hash = self.hash.write().await;
             self.cache.clear().await;
+            self.host_caches.write().await.clear();
 
             // Update https_client live
             self.https_client.reconfigure(&new_config.global).await;
 
+            self.history.write().await.push(
+                ConfigVersion {
+                    hash: new_config_hash,
+                    timestamp: Utc::now().timestamp(),
+                    source: source.to_string(),
+                    config_file: new_config.clone(),
+                },
+                new_config.global.reload.history.value(),
+            );
+
+            // Start and stop upstream health checks to match the new config
+            self.health.sync(&new_config, &self.https_client);
+            self.breakers.sync(&new_config);
+
+            // Reload listener certificates, if their settings changed
+            self.tls.sync(&new_config.global.server.tls);
+
             *config_file = new_config;
             *hash = new_config_hash;
         } else {
             log::debug!("Config has not changed");
         };
 
-        let mut last_read = self.last_read.write().await;
-        *last_read = now;
-
-        Ok(())
+        changed
     }
 
     pub async fn parse(
         &self,
         remote: Option<Url>,
         config_authentication: Option<ServerAuth>,
-    ) -> Result<ConfigFile, ProximaError> {
+    ) -> Result<(ConfigFile, ConfigSource), ProximaError> {
         let location = match remote {
             Some(url) => url.to_string(),
             None => self.location.clone(),
@@ -667,12 +1262,23 @@ impl Config {
                 // Create new get request
                 let mut req = Request::builder()
                     .method("GET")
-                    .uri(location)
+                    .uri(&location)
                     .body(Body::empty())
                     .expect("request builder");
 
-                // Add in basic auth if required
+                // Add in validators from the last fetch, if any
+                let previous = self.remote_configs.read().await.get(&location).cloned();
                 let headers = req.headers_mut();
+                if let Some(ref previous) = previous {
+                    if let Some(etag) = &previous.etag {
+                        headers.insert(IF_NONE_MATCH, etag.clone());
+                    }
+                    if let Some(last_modified) = &previous.last_modified {
+                        headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+                    }
+                }
+
+                // Add in basic auth if required
                 if let Some(authentication) = config_authentication {
                     log::debug!("Inserting auth for config endpoint");
                     authentication.headers(headers, uri).await?;
@@ -692,10 +1298,51 @@ impl Config {
                     404 => Err(ProximaError::NotFound),
                     403 => Err(ProximaError::Forbidden),
                     401 => Err(ProximaError::Unauthorized),
+                    304 => match previous {
+                        Some(previous) => {
+                            log::debug!("\"Config at {} has not been modified\"", &location);
+                            Ok((previous.config_file, previous.source))
+                        }
+                        None => Err(ProximaError::Unknown),
+                    },
                     200 => {
+                        // Pick format from content-type, then the url's extension, defaulting to json
+                        let format = response
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|h| h.to_str().ok())
+                            .and_then(ConfigFormat::from_content_type)
+                            .or_else(|| ConfigFormat::from_extension(Path::new(url.path())))
+                            .unwrap_or(ConfigFormat::Json);
+                        log::debug!("\"Parsing config at {} as {:?}\"", &location, format);
+
+                        let etag = response.headers().get(ETAG).cloned();
+                        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
                         let contents = hyper::body::to_bytes(response.into_body()).await?;
-                        let body = serde_json::from_slice(&contents)?;
-                        Ok(body)
+                        let config_file: ConfigFile = format.parse(&contents)?;
+                        let source = ConfigSource::Remote {
+                            format,
+                            contents: String::from_utf8_lossy(&contents).into_owned(),
+                        };
+
+                        // Save validators for the next fetch, dropping any stale ones when the
+                        // source stops sending them
+                        let mut remote_configs = self.remote_configs.write().await;
+                        if etag.is_some() || last_modified.is_some() {
+                            remote_configs.insert(
+                                location,
+                                RemoteConfig {
+                                    etag,
+                                    last_modified,
+                                    config_file: config_file.clone(),
+                                    source: source.clone(),
+                                },
+                            );
+                        } else {
+                            remote_configs.remove(&location);
+                        }
+
+                        Ok((config_file, source))
                     }
                     _ => {
                         log::error!(
@@ -708,13 +1355,64 @@ impl Config {
             }
             Err(e) => {
                 log::debug!("\"config location {} is not Url: {}\"", &self.location, e);
-                let mut file = File::open(self.location.clone())?;
-                let mut contents = String::new();
-
-                file.read_to_string(&mut contents)?;
-
-                Ok(serde_yaml::from_str(&contents)?)
+                ConfigLoader::load(&self.location)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECURED: &str = "
+routes:
+  secure:
+    proxy:
+      url: http://localhost:8080
+      authentication:
+        basic:
+          username: upstream
+          password: upstream-password
+      security:
+        client:
+          basic:
+          - username: user
+            password: testing
+";
+
+    #[tokio::test]
+    async fn snapshot_keeps_credentials() {
+        let directory =
+            std::env::temp_dir().join(format!("proxima-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let location = directory.join("config.yaml");
+        let snapshot = directory.join("snapshot.json").display().to_string();
+        std::fs::write(&location, SECURED).unwrap();
+
+        let mut config = Config::new(
+            &location.display().to_string(),
+            None,
+            HttpsClient::default(),
+            None,
+        );
+        config.snapshot = Some(snapshot.clone());
+        config.update().await.unwrap();
+
+        // Start from the snapshot, as if the config source were unreachable
+        let missing = directory.join("missing.yaml").display().to_string();
+        let mut restored = Config::new(&missing, None, HttpsClient::default(), None);
+        restored.snapshot = Some(snapshot);
+        restored.load_snapshot().await.unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let proxy = match restored.route("/secure").await {
+            Some(Route::Endpoint(Endpoint::Proxy(proxy))) => proxy,
+            other => panic!("expected proxy route, got {:?}", other),
+        };
+        let clients = proxy.security.and_then(|s| s.client).unwrap();
+        let basic = clients.basic.unwrap().into_iter().next().unwrap();
+        assert_eq!(basic.password, "testing");
+        assert!(proxy.authentication.is_some());
+    }
+}
@@ -1,4 +1,7 @@
+use crate::forwarded::ClientIpHeader;
 use crate::security::Security;
+use crate::tls::ServerTls;
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
@@ -8,6 +11,10 @@ pub struct GlobalConfig {
     pub network: GlobalConfigNetwork,
     #[serde(default)]
     pub security: GlobalConfigSecurity,
+    #[serde(default)]
+    pub reload: GlobalConfigReload,
+    #[serde(default)]
+    pub server: GlobalConfigServer,
     //    #[serde(skip_serializing_if = "Option::is_none")]
     //    pub integrations: Option<GlobalConfigIntegrations>
 }
@@ -23,6 +30,28 @@ pub struct GlobalConfigNetwork {
     pub reuse_address: bool,
     #[serde(default)]
     pub enforce_http: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trusted_proxies: Vec<IpNetwork>,
+    #[serde(default)]
+    pub client_ip_header: ClientIpHeader,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfigReload {
+    #[serde(default)]
+    pub interval: ReloadInterval,
+    #[serde(default)]
+    pub history: HistorySize,
+}
+
+// Settings for the proxy and API listeners
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct GlobalConfigServer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ServerTls>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
@@ -34,6 +63,8 @@ pub struct GlobalConfigSecurity {
     pub config: GlobalConfigSecurityConfig,
     #[serde(default)]
     pub auth: Option<Security>,
+    #[serde(default)]
+    pub api: Option<Security>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default, Hash)]
@@ -81,6 +112,36 @@ impl Timeout {
     }
 }
 
+impl ReloadInterval {
+    pub fn value(&self) -> i64 {
+        self.0
+    }
+}
+
+impl HistorySize {
+    pub fn value(&self) -> usize {
+        self.0
+    }
+}
+
+/// Reload interval in seconds, for configs at remote urls.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct ReloadInterval(i64);
+impl Default for ReloadInterval {
+    fn default() -> Self {
+        ReloadInterval(30)
+    }
+}
+
+/// Number of previous configs kept for diffs and rollbacks.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct HistorySize(usize);
+impl Default for HistorySize {
+    fn default() -> Self {
+        HistorySize(10)
+    }
+}
+
 /// Timeout in seconds.
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
 pub struct Timeout(u64);
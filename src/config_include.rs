@@ -0,0 +1,546 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::config::{ConfigFile, ConfigMap, Route, VirtualHost};
+use crate::config_global::GlobalConfig;
+use crate::error::Error as ProximaError;
+use crate::secrets;
+
+// A single config file, which may only contain some of the config
+#[derive(Deserialize, Debug, Default)]
+pub struct ConfigFragment {
+    #[serde(default)]
+    pub global: Option<GlobalConfig>,
+    #[serde(default)]
+    pub routes: ConfigMap,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, VirtualHost>,
+    #[serde(default)]
+    pub include: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+// The documents a config was read from, as written by their author. Snapshots keep these
+// rather than the parsed config, as credentials are left out when a config is serialized.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigSource {
+    Remote {
+        format: ConfigFormat,
+        contents: String,
+    },
+    // Local files in the order they were loaded, with includes following their parent
+    Local(Vec<SourceDocument>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SourceDocument {
+    pub path: PathBuf,
+    pub format: ConfigFormat,
+    pub contents: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ConfigLoader {
+    config: ConfigFile,
+    global_source: Option<PathBuf>,
+    sources: BTreeMap<String, PathBuf>,
+    visited: HashSet<PathBuf>,
+    documents: Vec<SourceDocument>,
+}
+
+// Only known config formats are read when loading a directory, skipping hidden files
+pub fn is_config_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .map(|f| f.to_string_lossy().starts_with('.'))
+        .unwrap_or(true);
+    !hidden && ConfigFormat::from_extension(path).is_some()
+}
+
+impl ConfigFormat {
+    pub fn from_extension(path: &Path) -> Option<ConfigFormat> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Some(ConfigFormat::Json),
+            Some("yaml") | Some("yml") => Some(ConfigFormat::Yaml),
+            Some("toml") => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<ConfigFormat> {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        match mime.as_str() {
+            "application/json" => Some(ConfigFormat::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" | "text/x-yaml" => {
+                Some(ConfigFormat::Yaml)
+            }
+            "application/toml" | "text/toml" | "text/x-toml" => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, contents: &[u8]) -> Result<T, ProximaError> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::from_slice(contents)?),
+            ConfigFormat::Yaml => Ok(serde_yaml::from_slice(contents)?),
+            ConfigFormat::Toml => Ok(toml::from_slice(contents)?),
+        }
+    }
+
+    pub fn serialize(&self, value: &serde_yaml::Value) -> Result<Vec<u8>, ProximaError> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::to_vec_pretty(value)?),
+            ConfigFormat::Yaml => Ok(serde_yaml::to_vec(value)?),
+            // Convert first, as toml requires plain values to come before tables
+            ConfigFormat::Toml => {
+                let value = toml::Value::try_from(value)?;
+                Ok(toml::to_string(&value)?.into_bytes())
+            }
+        }
+    }
+}
+
+impl ConfigSource {
+    // The route at keys, as written in the source with any secret references resolved
+    pub fn route(&self, keys: &[String]) -> Result<Option<serde_yaml::Value>, ProximaError> {
+        match self {
+            ConfigSource::Remote { format, contents } => {
+                let document: serde_yaml::Value = format.parse(contents.as_bytes())?;
+                Ok(find_route(&document, keys).cloned())
+            }
+            ConfigSource::Local(documents) => {
+                for document in documents {
+                    let parsed: serde_yaml::Value =
+                        document.format.parse(document.contents.as_bytes())?;
+                    if let Some(route) = find_route(&parsed, keys) {
+                        let mut route = route.clone();
+                        secrets::resolve(&mut route)?;
+                        return Ok(Some(route));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // The first included file which writes the route at keys, or routes within it. Only the
+    // main file is rewritten when routes are persisted, so these routes can't be.
+    pub fn included_route(&self, keys: &[String]) -> Result<Option<PathBuf>, ProximaError> {
+        match self {
+            ConfigSource::Remote { .. } => Ok(None),
+            ConfigSource::Local(documents) => {
+                for document in documents.iter().skip(1) {
+                    let parsed: serde_yaml::Value =
+                        document.format.parse(document.contents.as_bytes())?;
+                    if find_route(&parsed, keys).is_some() {
+                        return Ok(Some(document.path.clone()));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // Files and directories pulled in by include lists, relative to their including file
+    pub fn includes(&self) -> Vec<PathBuf> {
+        match self {
+            ConfigSource::Remote { .. } => Vec::new(),
+            ConfigSource::Local(documents) => documents
+                .iter()
+                .flat_map(|document| {
+                    let directory = document.path.parent().unwrap_or_else(|| Path::new("."));
+                    let value: Option<serde_yaml::Value> =
+                        document.format.parse(document.contents.as_bytes()).ok();
+                    value
+                        .as_ref()
+                        .and_then(|v| v.get("include"))
+                        .and_then(|v| v.as_sequence())
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|include| include.as_str())
+                        .map(|include| directory.join(include))
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        }
+    }
+
+    // Files referenced by secrets in local documents, which are watched for rotation
+    pub fn secret_files(&self) -> Vec<PathBuf> {
+        match self {
+            ConfigSource::Remote { .. } => Vec::new(),
+            ConfigSource::Local(documents) => documents
+                .iter()
+                .filter_map(|d| d.format.parse(d.contents.as_bytes()).ok())
+                .flat_map(|value: serde_yaml::Value| secrets::files(&value))
+                .collect(),
+        }
+    }
+
+    // Parse the config again, as it was when these documents were read
+    pub fn parse(&self) -> Result<ConfigFile, ProximaError> {
+        match self {
+            ConfigSource::Remote { format, contents } => format.parse(contents.as_bytes()),
+            ConfigSource::Local(documents) => {
+                let mut loader = ConfigLoader::default();
+                for document in documents {
+                    let fragment = ConfigLoader::fragment(
+                        &document.path,
+                        document.format,
+                        document.contents.as_bytes(),
+                    )?;
+                    // Includes were read as documents of their own
+                    loader.merge(&document.path, fragment)?;
+                }
+                Ok(loader.config)
+            }
+        }
+    }
+}
+
+fn find_route<'a>(
+    document: &'a serde_yaml::Value,
+    keys: &[String],
+) -> Option<&'a serde_yaml::Value> {
+    keys.iter()
+        .try_fold(document.get("routes")?, |current, key| {
+            current.get(key.as_str())
+        })
+}
+
+impl ConfigLoader {
+    // Load a config file, or every config file in a directory, along with any includes
+    pub fn load(location: &str) -> Result<(ConfigFile, ConfigSource), ProximaError> {
+        let mut loader = ConfigLoader::default();
+        loader.load_path(Path::new(location))?;
+        Ok((loader.config, ConfigSource::Local(loader.documents)))
+    }
+
+    fn load_path(&mut self, path: &Path) -> Result<(), ProximaError> {
+        if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && is_config_file(p))
+                .collect();
+            files.sort();
+
+            log::debug!(
+                "\"Loading {} config files from {}\"",
+                files.len(),
+                path.display()
+            );
+            for file in files {
+                self.load_file(&file)?;
+            }
+            Ok(())
+        } else {
+            self.load_file(path)
+        }
+    }
+
+    fn load_file(&mut self, path: &Path) -> Result<(), ProximaError> {
+        // Skip files that have already been loaded, to avoid include loops
+        let canonical = path.canonicalize()?;
+        if !self.visited.insert(canonical) {
+            log::debug!("\"Skipping already loaded config {}\"", path.display());
+            return Ok(());
+        }
+
+        log::debug!("\"Loading config file {}\"", path.display());
+        let contents = std::fs::read(path)?;
+        let format = ConfigFormat::from_extension(path).unwrap_or(ConfigFormat::Yaml);
+        let mut fragment = ConfigLoader::fragment(path, format, &contents)?;
+        self.documents.push(SourceDocument {
+            path: path.to_path_buf(),
+            format,
+            contents: String::from_utf8_lossy(&contents).into_owned(),
+        });
+
+        let includes = std::mem::take(&mut fragment.include);
+        self.merge(path, fragment)?;
+
+        // Includes are relative to the including file
+        let directory = path.parent().unwrap_or_else(|| Path::new("."));
+        for include in includes {
+            self.load_path(&directory.join(include))?;
+        }
+
+        Ok(())
+    }
+
+    fn fragment(
+        path: &Path,
+        format: ConfigFormat,
+        contents: &[u8],
+    ) -> Result<ConfigFragment, ProximaError> {
+        let unparseable = |e: ProximaError| {
+            ProximaError::UnparseableConfig(path.display().to_string(), e.to_string())
+        };
+
+        // Secret references are only resolved for local files
+        let mut value: serde_yaml::Value = format.parse(contents).map_err(unparseable)?;
+        secrets::resolve(&mut value)?;
+        serde_yaml::from_value(value).map_err(|e| unparseable(ProximaError::SerdeYaml(e)))
+    }
+
+    fn merge(&mut self, path: &Path, fragment: ConfigFragment) -> Result<(), ProximaError> {
+        if let Some(global) = fragment.global {
+            if let Some(existing) = &self.global_source {
+                return Err(ProximaError::ConfigCollision(
+                    "global".to_string(),
+                    existing.display().to_string(),
+                    path.display().to_string(),
+                ));
+            }
+            self.config.global = global;
+            self.global_source = Some(path.to_path_buf());
+        }
+
+        let mut routes = std::mem::take(&mut self.config.routes);
+        let merged = self.merge_routes(&mut routes, fragment.routes, "", path);
+        self.config.routes = routes;
+        merged?;
+
+        for (host, vhost) in fragment.hosts {
+            let key = format!("hosts.{}", host);
+            if let Some(existing) = self.sources.get(&key) {
+                return Err(ProximaError::ConfigCollision(
+                    key,
+                    existing.display().to_string(),
+                    path.display().to_string(),
+                ));
+            }
+            self.sources.insert(key, path.to_path_buf());
+            self.config.hosts.insert(host, vhost);
+        }
+
+        Ok(())
+    }
+
+    // Merge routes into existing routes, recursing into folders found in both
+    fn merge_routes(
+        &mut self,
+        existing: &mut ConfigMap,
+        routes: ConfigMap,
+        prefix: &str,
+        path: &Path,
+    ) -> Result<(), ProximaError> {
+        for (key, route) in routes {
+            let name = match prefix {
+                "" => key.clone(),
+                _ => format!("{}/{}", prefix, key),
+            };
+
+            match (existing.get_mut(&key), route) {
+                (None, route) => {
+                    self.sources.insert(name, path.to_path_buf());
+                    existing.insert(key, route);
+                }
+                (Some(Route::ConfigMap(current)), Route::ConfigMap(map)) => {
+                    self.merge_routes(current, *map, &name, path)?;
+                }
+                (Some(_), _) => {
+                    return Err(ProximaError::ConfigCollision(
+                        name.clone(),
+                        self.source(&name),
+                        path.display().to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Find the file a route came from, which may have been added as part of a parent folder
+    fn source(&self, name: &str) -> String {
+        let mut current = name;
+        loop {
+            if let Some(source) = self.sources.get(current) {
+                return source.display().to_string();
+            }
+            match current.rsplit_once('/') {
+                Some((parent, _)) => current = parent,
+                None => return "unknown".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USERS: &str = "
+routes:
+  api:
+    users:
+      proxy:
+        url: http://users:8080
+";
+
+    // Write config files into a directory of their own, returning the directory
+    fn files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("proxima-include-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        for (file, contents) in files {
+            let path = directory.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        directory
+    }
+
+    fn load(directory: &Path, file: &str) -> Result<(ConfigFile, ConfigSource), ProximaError> {
+        ConfigLoader::load(&directory.join(file).display().to_string())
+    }
+
+    fn collision(
+        result: Result<(ConfigFile, ConfigSource), ProximaError>,
+    ) -> (String, String, String) {
+        match result {
+            Err(ProximaError::ConfigCollision(name, first, second)) => (
+                name,
+                Path::new(&first)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned(),
+                Path::new(&second)
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            other => panic!("expected a collision, got {:?}", other.map(|(c, _)| c)),
+        }
+    }
+
+    #[test]
+    fn folders_merge_across_files() {
+        let directory = files(
+            "merge",
+            &[
+                ("main.yaml", "include: [routes]\nroutes: {}\n"),
+                ("routes/a.yaml", USERS),
+                (
+                    "routes/b.json",
+                    r#"{"routes": {"api": {"orders": {"proxy": {"url": "http://orders:8080"}}}}}"#,
+                ),
+            ],
+        );
+        let (config, source) = load(&directory, "main.yaml").unwrap();
+        match &config.routes["api"] {
+            Route::ConfigMap(api) => {
+                assert!(api.contains_key("users"));
+                assert!(api.contains_key("orders"));
+            }
+            Route::Endpoint(_) => panic!("api should be a folder"),
+        }
+
+        let keys = vec!["api".to_string(), "orders".to_string()];
+        assert_eq!(
+            source.included_route(&keys).unwrap(),
+            Some(directory.join("routes").join("b.json"))
+        );
+        assert!(source.route(&keys).unwrap().is_some());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn routes_collide() {
+        let directory = files("routes", &[("a.yaml", USERS), ("b.yaml", USERS)]);
+        assert_eq!(
+            collision(ConfigLoader::load(&directory.display().to_string())),
+            (
+                "api/users".to_string(),
+                "a.yaml".to_string(),
+                "b.yaml".to_string()
+            )
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        // A folder in one file can't replace an endpoint in another
+        let directory = files(
+            "folder",
+            &[
+                ("a.yaml", USERS),
+                (
+                    "b.yaml",
+                    "routes:\n  api:\n    users:\n      v2:\n        static:\n          body: ok\n",
+                ),
+            ],
+        );
+        assert_eq!(
+            collision(ConfigLoader::load(&directory.display().to_string())).0,
+            "api/users"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn global_and_hosts_collide() {
+        let directory = files(
+            "global",
+            &[
+                ("main.yaml", "include: [other.yaml]\nglobal: {}\n"),
+                ("other.yaml", "global: {}\n"),
+            ],
+        );
+        assert_eq!(
+            collision(load(&directory, "main.yaml")),
+            (
+                "global".to_string(),
+                "main.yaml".to_string(),
+                "other.yaml".to_string()
+            )
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let host = "hosts:\n  example.com:\n    routes: {}\n";
+        let directory = files("hosts", &[("a.yaml", host), ("b.yaml", host)]);
+        assert_eq!(
+            collision(ConfigLoader::load(&directory.display().to_string())).0,
+            "hosts.example.com"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn include_loops_are_skipped() {
+        let directory = files(
+            "loop",
+            &[
+                ("a.yaml", "include: [b.yaml]\nroutes: {}\n"),
+                (
+                    "b.yaml",
+                    "include: [a.yaml]\nroutes:\n  b:\n    static:\n      body: ok\n",
+                ),
+            ],
+        );
+        let (config, source) = load(&directory, "a.yaml").unwrap();
+        assert!(config.routes.contains_key("b"));
+        match source {
+            ConfigSource::Local(documents) => assert_eq!(documents.len(), 2),
+            ConfigSource::Remote { .. } => panic!("expected local documents"),
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -29,9 +29,18 @@ pub enum Error {
     PathCount,
     UnmatchedHeader,
     RefreshLock,
+    ConfigCollision(String, String, String),
+    UnparseableConfig(String, String),
+    UnresolvedSecret(String),
+    InvalidRoute(String),
+    IncludedRoute(String, String),
+    UnknownVersion(String),
+    CircuitOpen,
     Hyper(hyper::Error),
     SerdeJson(serde_json::Error),
     SerdeYaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+    TomlSerialize(toml::ser::Error),
     File(std::io::Error),
     InvalidUri(hyper::http::uri::InvalidUri),
     Jwt(jsonwebtoken::errors::Error),
@@ -72,9 +81,36 @@ impl fmt::Display for Error {
             Error::UnmatchedHeader => {
                 f.write_str("{\"error\": \"Incorrect header for auth type\"}")
             }
+            Error::ConfigCollision(ref route, ref first, ref second) => write!(
+                f,
+                "{{\"error\": \"{} in {} collides with {}\"}}",
+                route, second, first
+            ),
+            Error::UnparseableConfig(ref file, ref err) => {
+                write!(f, "{{\"error\": \"Unable to parse {}: {}\"}}", file, err)
+            }
+            Error::UnresolvedSecret(ref err) => {
+                write!(f, "{{\"error\": \"Unable to resolve secret: {}\"}}", err)
+            }
+            Error::InvalidRoute(ref err) => {
+                write!(f, "{{\"error\": \"Invalid route: {}\"}}", err)
+            }
+            Error::IncludedRoute(ref route, ref file) => write!(
+                f,
+                "{{\"error\": \"{} is defined in included file {}, and can't be persisted\"}}",
+                route, file
+            ),
+            Error::UnknownVersion(ref hash) => {
+                write!(f, "{{\"error\": \"No config in history for {}\"}}", hash)
+            }
+            Error::CircuitOpen => {
+                f.write_str("{\"error\": \"No upstream available, circuit open\"}")
+            }
             Error::Hyper(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::SerdeJson(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::SerdeYaml(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::Toml(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
+            Error::TomlSerialize(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::File(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::InvalidUri(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
             Error::Jwt(ref err) => write!(f, "{{\"error\": \"{}\"}}", err),
@@ -122,6 +158,10 @@ impl IntoResponse for Error {
                 StatusCode::UNAUTHORIZED
             }
             Error::ConnectionTimeout => StatusCode::GATEWAY_TIMEOUT,
+            Error::InvalidRoute(_) => StatusCode::BAD_REQUEST,
+            Error::IncludedRoute(_, _) => StatusCode::CONFLICT,
+            Error::UnknownVersion(_) => StatusCode::NOT_FOUND,
+            Error::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -149,6 +189,18 @@ impl From<serde_yaml::Error> for Error {
     }
 }
 
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Toml(err)
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Error {
+        Error::TomlSerialize(err)
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
         Error::File(err)
@@ -0,0 +1,433 @@
+use hyper::header::{HeaderName, HeaderValue, FORWARDED};
+use hyper::HeaderMap;
+use ipnetwork::IpNetwork;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::error::Error as ProximaError;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// How X-Forwarded-* and Forwarded headers are sent to the upstream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeaders {
+    // Add this hop to any chain sent by the client
+    #[default]
+    Append,
+    // Discard any chain sent by the client, and start a new one from this hop
+    Replace,
+    // Send no forwarding headers, including any sent by the client
+    Omit,
+}
+
+// Header trusted proxies record the client address in
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+// The connection proxima received the request on. The peer is the address connected to
+// proxima, and the client is the address resolved through any trusted proxies.
+pub struct Hop<'a> {
+    pub peer: IpAddr,
+    pub client: IpAddr,
+    // Whether the peer is a trusted proxy
+    pub trusted: bool,
+    pub proto: &'a str,
+    pub host: Option<&'a str>,
+}
+
+impl ForwardedHeaders {
+    pub fn apply(&self, map: &mut HeaderMap, hop: &Hop) -> Result<(), ProximaError> {
+        // Clients could otherwise claim any address, proto or host, so a chain is only
+        // appended to when sent by a trusted proxy
+        if *self != ForwardedHeaders::Append || !hop.trusted {
+            log::debug!("\"Removing client forwarding headers\"");
+            for name in [
+                X_FORWARDED_FOR,
+                X_FORWARDED_PROTO,
+                X_FORWARDED_HOST,
+                FORWARDED,
+            ] {
+                map.remove(name);
+            }
+        }
+        if *self == ForwardedHeaders::Omit {
+            return Ok(());
+        }
+
+        // A replaced chain starts from the client, as resolved through any trusted proxies
+        let node = match self {
+            ForwardedHeaders::Replace => hop.client,
+            _ => hop.peer,
+        };
+        Self::append(map, X_FORWARDED_FOR, &node.to_string())?;
+
+        // Proto and host describe the original request, so the first trusted proxy's values
+        // are kept
+        if !map.contains_key(X_FORWARDED_PROTO) {
+            map.insert(X_FORWARDED_PROTO, HeaderValue::from_str(hop.proto)?);
+        }
+        if let Some(host) = hop.host {
+            if !map.contains_key(X_FORWARDED_HOST) {
+                map.insert(X_FORWARDED_HOST, HeaderValue::from_str(host)?);
+            }
+        }
+
+        Self::append(map, FORWARDED, &Self::element(node, hop))
+    }
+
+    // Lists can be split over several header lines, which are joined before appending
+    fn append(map: &mut HeaderMap, name: HeaderName, value: &str) -> Result<(), ProximaError> {
+        let mut chain: Vec<&str> = map
+            .get_all(&name)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .map(|h| h.trim())
+            .filter(|h| !h.is_empty())
+            .collect();
+        chain.push(value);
+        let value = HeaderValue::from_str(&chain.join(", "))?;
+        map.insert(name, value);
+        Ok(())
+    }
+
+    // RFC 7239 forwarded-element, such as for=192.0.2.60;proto=http;host=example.com
+    fn element(node: IpAddr, hop: &Hop) -> String {
+        let node = match node {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+        };
+        let mut element = format!("for={};proto={}", node, hop.proto);
+        if let Some(host) = hop.host {
+            element.push_str(&format!(";host={}", quote(host)));
+        }
+        element
+    }
+}
+
+// Values which aren't RFC 7230 tokens, such as hosts with a port, must be quoted
+fn quote(value: &str) -> String {
+    let token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+// Forwarding headers are only believed when the peer is a trusted proxy. The chain is then
+// walked from the right, skipping trusted proxies, and the first untrusted address is the client.
+// The client is unresolved when the walk reaches an entry which can't be parsed, such as an
+// obfuscated or unknown node, or runs out of entries without leaving the trusted proxies.
+pub fn client_addr(
+    headers: &HeaderMap,
+    peer: SocketAddr,
+    trusted_proxies: &[IpNetwork],
+    header: ClientIpHeader,
+) -> Option<SocketAddr> {
+    let trusted = |addr: &SocketAddr| is_trusted(trusted_proxies, addr.ip());
+    if !trusted(&peer) {
+        return Some(peer);
+    }
+
+    for node in chain(headers, header).iter().rev() {
+        match parse_node(node) {
+            Some(addr) if trusted(&addr) => continue,
+            Some(addr) => return Some(addr),
+            None => {
+                log::debug!("\"Unable to parse forwarded node {}\"", node);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+// Stand in for a client which couldn't be resolved. The unspecified address of the peer's
+// family is used, so that network whitelists don't match a trusted proxy in place of the client.
+pub fn unresolved(peer: SocketAddr) -> SocketAddr {
+    let ip = match peer.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+pub fn is_trusted(trusted_proxies: &[IpNetwork], ip: IpAddr) -> bool {
+    trusted_proxies.iter().any(|n| n.contains(ip))
+}
+
+// Nodes of the forwarding chain, from the original client to the last proxy
+fn chain(headers: &HeaderMap, header: ClientIpHeader) -> Vec<String> {
+    let values = headers
+        .get_all(match header {
+            ClientIpHeader::XForwardedFor => X_FORWARDED_FOR,
+            ClientIpHeader::Forwarded => FORWARDED,
+        })
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(split_list)
+        .map(|element| element.trim().to_string());
+
+    match header {
+        ClientIpHeader::XForwardedFor => values.collect(),
+        // Only the for= parameter of each forwarded-element names a node
+        ClientIpHeader::Forwarded => values
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, value)| value.trim().trim_matches('"').to_string())
+                    .unwrap_or_default()
+            })
+            .collect(),
+    }
+}
+
+// Split a header list on commas outside of quoted strings
+fn split_list(value: &str) -> Vec<&str> {
+    let mut elements = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                elements.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    elements.push(&value[start..]);
+    elements
+}
+
+// Nodes can be an ip, an ip and port, or a bracketed IPv6 address with an optional port
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .unwrap_or(node);
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        map
+    }
+
+    fn hop(trusted: bool) -> Hop<'static> {
+        Hop {
+            peer: "198.51.100.17".parse().unwrap(),
+            client: "192.0.2.60".parse().unwrap(),
+            trusted,
+            proto: "http",
+            host: Some("example.com"),
+        }
+    }
+
+    const CLIENT_HEADERS: &[(&str, &str)] = &[
+        ("x-forwarded-for", "192.0.2.60"),
+        ("x-forwarded-proto", "https"),
+        ("x-forwarded-host", "spoofed.example"),
+        ("forwarded", "for=192.0.2.60;proto=https"),
+    ];
+
+    #[test]
+    fn append_from_trusted_proxy() {
+        let mut map = headers(CLIENT_HEADERS);
+        ForwardedHeaders::Append
+            .apply(&mut map, &hop(true))
+            .unwrap();
+        assert_eq!(map[X_FORWARDED_FOR], "192.0.2.60, 198.51.100.17");
+        assert_eq!(map[X_FORWARDED_PROTO], "https");
+        assert_eq!(map[X_FORWARDED_HOST], "spoofed.example");
+        assert_eq!(
+            map[FORWARDED],
+            "for=192.0.2.60;proto=https, for=198.51.100.17;proto=http;host=example.com"
+        );
+    }
+
+    #[test]
+    fn append_from_untrusted_peer() {
+        let mut map = headers(CLIENT_HEADERS);
+        ForwardedHeaders::Append
+            .apply(&mut map, &hop(false))
+            .unwrap();
+        assert_eq!(map[X_FORWARDED_FOR], "198.51.100.17");
+        assert_eq!(map[X_FORWARDED_PROTO], "http");
+        assert_eq!(map[X_FORWARDED_HOST], "example.com");
+        assert_eq!(
+            map[FORWARDED],
+            "for=198.51.100.17;proto=http;host=example.com"
+        );
+    }
+
+    #[test]
+    fn replace_starts_from_client() {
+        let mut map = headers(CLIENT_HEADERS);
+        ForwardedHeaders::Replace
+            .apply(&mut map, &hop(true))
+            .unwrap();
+        assert_eq!(map[X_FORWARDED_FOR], "192.0.2.60");
+        assert_eq!(map[X_FORWARDED_PROTO], "http");
+        assert_eq!(map[FORWARDED], "for=192.0.2.60;proto=http;host=example.com");
+    }
+
+    #[test]
+    fn omit_removes_all() {
+        let mut map = headers(CLIENT_HEADERS);
+        ForwardedHeaders::Omit.apply(&mut map, &hop(true)).unwrap();
+        assert!(map.is_empty());
+    }
+
+    fn trusted() -> Vec<IpNetwork> {
+        vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8:ffff::/48".parse().unwrap(),
+        ]
+    }
+
+    fn client(pairs: &[(&str, &str)], peer: &str, header: ClientIpHeader) -> Option<SocketAddr> {
+        client_addr(&headers(pairs), peer.parse().unwrap(), &trusted(), header)
+    }
+
+    #[test]
+    fn client_from_untrusted_peer_ignores_headers() {
+        let pairs = [("x-forwarded-for", "192.0.2.60")];
+        assert_eq!(
+            client(&pairs, "198.51.100.17:4000", ClientIpHeader::XForwardedFor),
+            "198.51.100.17:4000".parse().ok()
+        );
+    }
+
+    #[test]
+    fn client_skips_trusted_proxies() {
+        let pairs = [
+            ("x-forwarded-for", "203.0.113.9, 192.0.2.60"),
+            ("x-forwarded-for", "10.1.1.1"),
+        ];
+        assert_eq!(
+            client(&pairs, "10.0.0.2:4000", ClientIpHeader::XForwardedFor),
+            "192.0.2.60:0".parse().ok()
+        );
+    }
+
+    #[test]
+    fn client_unresolved_when_chain_is_all_trusted() {
+        let pairs = [("x-forwarded-for", "10.1.1.1, 10.2.2.2")];
+        assert_eq!(
+            client(&pairs, "10.0.0.2:4000", ClientIpHeader::XForwardedFor),
+            None
+        );
+        assert_eq!(
+            client(&[], "10.0.0.2:4000", ClientIpHeader::XForwardedFor),
+            None
+        );
+    }
+
+    #[test]
+    fn client_unresolved_at_unparseable_node() {
+        let pairs = [("x-forwarded-for", "192.0.2.60, unknown, 10.1.1.1")];
+        assert_eq!(
+            client(&pairs, "10.0.0.2:4000", ClientIpHeader::XForwardedFor),
+            None
+        );
+    }
+
+    #[test]
+    fn client_from_forwarded_header() {
+        let pairs = [(
+            "forwarded",
+            "for=192.0.2.60;proto=http, for=\"[2001:db8:ffff::1]:8080\";by=203.0.113.43",
+        )];
+        assert_eq!(
+            client(&pairs, "10.0.0.2:4000", ClientIpHeader::Forwarded),
+            "192.0.2.60:0".parse().ok()
+        );
+
+        // Elements without a for= parameter leave the client unresolved
+        let pairs = [("forwarded", "for=192.0.2.60, by=10.1.1.1")];
+        assert_eq!(
+            client(&pairs, "10.0.0.2:4000", ClientIpHeader::Forwarded),
+            None
+        );
+    }
+
+    #[test]
+    fn unresolved_keeps_address_family() {
+        assert_eq!(
+            unresolved("10.0.0.2:4000".parse().unwrap()),
+            "0.0.0.0:0".parse().unwrap()
+        );
+        assert_eq!(
+            unresolved("[2001:db8:ffff::1]:4000".parse().unwrap()),
+            "[::]:0".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn split_list_respects_quotes() {
+        assert_eq!(
+            split_list("for=\"a,b\";proto=http, for=c"),
+            vec!["for=\"a,b\";proto=http", " for=c"]
+        );
+        assert_eq!(split_list("a,,b"), vec!["a", "", "b"]);
+        assert_eq!(split_list(""), vec![""]);
+    }
+
+    #[test]
+    fn parse_node_forms() {
+        assert_eq!(parse_node("192.0.2.60"), "192.0.2.60:0".parse().ok());
+        assert_eq!(
+            parse_node("192.0.2.60:4711"),
+            "192.0.2.60:4711".parse().ok()
+        );
+        assert_eq!(parse_node("2001:db8::1"), "[2001:db8::1]:0".parse().ok());
+        assert_eq!(parse_node("[2001:db8::1]"), "[2001:db8::1]:0".parse().ok());
+        assert_eq!(
+            parse_node("[2001:db8::1]:4711"),
+            "[2001:db8::1]:4711".parse().ok()
+        );
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+        assert_eq!(parse_node(""), None);
+    }
+
+    #[test]
+    fn element_quotes_ipv6_and_ports() {
+        let hop = Hop {
+            peer: "2001:db8::1".parse().unwrap(),
+            host: Some("example.com:8080"),
+            ..hop(false)
+        };
+        assert_eq!(
+            ForwardedHeaders::element(hop.peer, &hop),
+            "for=\"[2001:db8::1]\";proto=http;host=\"example.com:8080\""
+        );
+    }
+}
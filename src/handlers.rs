@@ -1,7 +1,8 @@
 use axum::{
     async_trait,
     extract::{
-        BodyStream, ConnectInfo, Extension, FromRequest, OriginalUri, Query, RawQuery, RequestParts,
+        ConnectInfo, Extension, FromRequest, OriginalUri, Path, Query, RawBody, RawQuery,
+        RequestParts,
     },
     http::HeaderName,
     http::Response,
@@ -10,8 +11,10 @@ use axum::{
     Json,
 };
 use http::header::FORWARDED;
+use http::header::HOST;
 use http::header::USER_AGENT;
 use http::HeaderValue;
+use hyper::upgrade::OnUpgrade;
 use hyper::{Body, HeaderMap};
 use metrics_exporter_prometheus::PrometheusHandle;
 use rand::distributions::Alphanumeric;
@@ -23,13 +26,23 @@ use std::net::SocketAddr;
 use std::time::Instant;
 
 use crate::error::Error as ProximaError;
+use crate::history::DiffParams;
 use crate::path::ProxyPath;
+use crate::route_edit::{RouteEdit, RouteEditParams};
+use crate::upgrade;
 use crate::State;
 
 // This is required in order to get the method from the request
 #[derive(Debug)]
 pub struct RequestMethod(pub hyper::Method);
 
+// This is required in order to get the host, minus any port, from the request
+#[derive(Debug)]
+pub struct RequestHost(pub Option<String>);
+
+// This is required in order to take over the client connection for upgrade requests
+pub struct ClientUpgrade(pub Option<OnUpgrade>);
+
 // This is for accessing the cache
 #[derive(Deserialize)]
 pub struct CacheParams {
@@ -49,6 +62,47 @@ where
     }
 }
 
+#[async_trait]
+impl<B> FromRequest<B> for RequestHost
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        // HTTP/2 requests carry the :authority pseudo header in the uri
+        let host = match req.uri().host() {
+            Some(h) => Some(h.to_owned()),
+            None => req
+                .headers()
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| match h.rsplit_once(':') {
+                    Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => {
+                        name.to_owned()
+                    }
+                    _ => h.to_owned(),
+                }),
+        };
+        Ok(Self(host.map(|h| h.to_lowercase())))
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for ClientUpgrade
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if !upgrade::is_upgrade(req.headers()) {
+            return Ok(Self(None));
+        }
+        Ok(Self(req.extensions_mut().remove::<OnUpgrade>()))
+    }
+}
+
 pub async fn metrics(
     Extension(recorder_handle): Extension<PrometheusHandle>,
 ) -> Result<String, ProximaError> {
@@ -56,14 +110,17 @@ pub async fn metrics(
     Ok(recorder_handle.render())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy(
     Extension(mut state): Extension<State>,
     path: ProxyPath,
     RequestMethod(method): RequestMethod,
+    RequestHost(host): RequestHost,
     all_headers: HeaderMap,
     RawQuery(query): RawQuery,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    payload: Option<BodyStream>,
+    ClientUpgrade(upgrade): ClientUpgrade,
+    payload: Option<RawBody>,
 ) -> Result<Response<Body>, ProximaError> {
     // Check for forwarded for
     let forwarded_for = if let Some(x_forwarded) = all_headers.get("x-forwarded-for") {
@@ -106,8 +163,11 @@ pub async fn proxy(
             path.clone(),
             query.clone(),
             all_headers,
-            payload,
+            payload.map(|RawBody(body)| body),
             addr.clone(),
+            host,
+            upgrade,
+            id.clone(),
         )
         .await
     {
@@ -172,7 +232,56 @@ pub async fn config(
         &method,
         &addr,
     );
-    Json(json!(state.config().await.global))
+    let mut payload = json!(state.config().await.global);
+    payload["serving_snapshot"] = json!(state.config.serving_snapshot().await);
+    Json(payload)
+}
+
+pub async fn config_history(
+    Extension(state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Json<Value> {
+    log::debug!(
+        "{{\"fn\": \"config_history\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/config/history\"}}",
+        &method,
+        &addr,
+    );
+    Json(json!(state.config.history().await))
+}
+
+pub async fn config_diff(
+    Extension(state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<DiffParams>,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"config_diff\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/config/diff\"}}",
+        &method,
+        &addr,
+    );
+    Ok(Json(state.config.diff(&params).await?))
+}
+
+pub async fn config_rollback(
+    Extension(mut state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"config_rollback\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/config/rollback/{}\"}}",
+        &method,
+        &addr,
+        &hash,
+    );
+    state.authorize_admin(&headers, &method, &addr).await?;
+    state.config.rollback(&hash).await?;
+    Ok(Json(
+        json!({"msg": "config has been rolled back", "hash": hash}),
+    ))
 }
 
 pub async fn routes(
@@ -188,6 +297,129 @@ pub async fn routes(
     Json(json!(state.config().await.routes))
 }
 
+pub async fn route_get(
+    Extension(state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"route_get\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/routes{}\"}}",
+        &method,
+        &addr,
+        &path,
+    );
+    let route = state
+        .config
+        .route(path.trim_matches('/'))
+        .await
+        .ok_or(ProximaError::UnknownProxy)?;
+    Ok(Json(json!(route)))
+}
+
+pub async fn route_put(
+    Extension(mut state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+    Query(params): Query<RouteEditParams>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"route_put\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/routes{}\"}}",
+        &method,
+        &addr,
+        &path,
+    );
+    state.authorize_admin(&headers, &method, &addr).await?;
+    let path = path.trim_matches('/');
+    state
+        .config
+        .edit_route(path, RouteEdit::Put(payload), params.persist)
+        .await?;
+    Ok(Json(
+        json!({"msg": "route has been updated", "route": path}),
+    ))
+}
+
+pub async fn route_patch(
+    Extension(mut state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+    Query(params): Query<RouteEditParams>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"route_patch\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/routes{}\"}}",
+        &method,
+        &addr,
+        &path,
+    );
+    state.authorize_admin(&headers, &method, &addr).await?;
+    let path = path.trim_matches('/');
+    state
+        .config
+        .edit_route(path, RouteEdit::Patch(payload), params.persist)
+        .await?;
+    Ok(Json(
+        json!({"msg": "route has been updated", "route": path}),
+    ))
+}
+
+pub async fn route_delete(
+    Extension(mut state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(path): Path<String>,
+    Query(params): Query<RouteEditParams>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, ProximaError> {
+    log::debug!(
+        "{{\"fn\": \"route_delete\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/routes{}\"}}",
+        &method,
+        &addr,
+        &path,
+    );
+    state.authorize_admin(&headers, &method, &addr).await?;
+    let path = path.trim_matches('/');
+    state
+        .config
+        .edit_route(path, RouteEdit::Delete, params.persist)
+        .await?;
+    Ok(Json(
+        json!({"msg": "route has been removed", "route": path}),
+    ))
+}
+
+pub async fn upstreams(
+    Extension(state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Json<Value> {
+    log::debug!(
+        "{{\"fn\": \"upstreams\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/upstreams\"}}",
+        &method,
+        &addr,
+    );
+    Json(json!(state.config.health.members()))
+}
+
+pub async fn circuits(
+    Extension(state): Extension<State>,
+    RequestMethod(method): RequestMethod,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Json<Value> {
+    log::debug!(
+        "{{\"fn\": \"circuits\", \"method\": \"{}\", \"addr\":\"{}\", \"path\":\"/upstreams/circuits\"}}",
+        &method,
+        &addr,
+    );
+    Json(json!(state.config.circuits().await))
+}
+
 pub async fn mappings_get(
     Extension(mut state): Extension<State>,
     RequestMethod(method): RequestMethod,
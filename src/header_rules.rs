@@ -0,0 +1,106 @@
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+use crate::config::Header;
+use crate::error::Error as ProximaError;
+use crate::path::ProxyPath;
+
+// Headers are removed first, then set, replacing any existing values, then added alongside
+// any existing values
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRules {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub set: Vec<Header>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<Header>,
+}
+
+// Values available to header templates, alongside any params captured from the route key
+pub struct HeaderContext<'a> {
+    pub path: &'a ProxyPath,
+    pub client_addr: &'a SocketAddr,
+    pub trace_id: &'a str,
+    pub identity: Option<&'a str>,
+}
+
+impl HeaderContext<'_> {
+    pub fn render(&self, template: &str) -> String {
+        // The part of the request path matched by the route key
+        let route = format!("/{}", self.path.key().unwrap_or_default());
+        self.path.interpolate_with(template, |name| match name {
+            "client_ip" => Some(self.client_addr.ip().to_string()),
+            "trace_id" => Some(self.trace_id.to_string()),
+            "route" => Some(route.clone()),
+            "identity" => Some(self.identity.unwrap_or_default().to_string()),
+            _ => None,
+        })
+    }
+}
+
+impl HeaderRules {
+    // Rendered values can still be invalid, such as a param holding a newline, in which case
+    // the header is skipped rather than failing the request
+    pub fn apply(&self, map: &mut HeaderMap, context: &HeaderContext) {
+        for name in &self.remove {
+            log::debug!("\"Removing header {}\"", name);
+            map.remove(name.as_str());
+        }
+        for header in &self.set {
+            if let Some((name, value)) = Self::render(header, context) {
+                log::debug!("\"Setting header {}\"", name);
+                map.insert(name, value);
+            }
+        }
+        for header in &self.add {
+            if let Some((name, value)) = Self::render(header, context) {
+                log::debug!("\"Adding header {}\"", name);
+                map.append(name, value);
+            }
+        }
+    }
+
+    // Problems with header names and templates, checked as the config is validated
+    pub fn issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        for name in &self.remove {
+            if let Err(e) = HeaderName::from_bytes(name.as_bytes()) {
+                issues.push(format!("header {}: {}", name, e));
+            }
+        }
+        for header in self.set.iter().chain(&self.add) {
+            if let Err(e) = HeaderName::from_bytes(header.name.as_bytes()) {
+                issues.push(format!("header {}: {}", header.name, e));
+            }
+            if let Err(e) = HeaderValue::from_str(&header.value) {
+                issues.push(format!("header {} value: {}", header.name, e));
+            }
+        }
+        issues
+    }
+
+    // Headers rendering to an empty value are skipped, such as {identity} for anonymous clients
+    fn render(header: &Header, context: &HeaderContext) -> Option<(HeaderName, HeaderValue)> {
+        let value = context.render(&header.value);
+        if value.is_empty() {
+            return None;
+        }
+        let rendered = HeaderName::from_lowercase(header.name.to_lowercase().as_bytes())
+            .map_err(ProximaError::from)
+            .and_then(|name| Ok((name, HeaderValue::from_str(&value)?)));
+        match rendered {
+            Ok(header) => Some(header),
+            Err(e) => {
+                log::warn!("\"Skipping header {}: {}\"", header.name, e);
+                None
+            }
+        }
+    }
+}
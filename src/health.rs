@@ -0,0 +1,361 @@
+use axum::http::Request;
+use chrono::Utc;
+use hyper::http::uri::InvalidUri;
+use hyper::{Body, Uri};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use crate::auth::server::ServerAuth;
+use crate::config::{Config, ConfigFile, Proxy};
+use crate::https::{HttpsClient, Protocol};
+use crate::urls::Urls;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    pub path: String,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default = "default_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
+// Probe every 10 seconds by default
+fn default_interval() -> u64 {
+    10000
+}
+
+// Default probe timeout of 2 seconds
+fn default_timeout() -> u64 {
+    2000
+}
+
+fn default_threshold() -> u32 {
+    2
+}
+
+impl HealthCheck {
+    pub fn probe_url(&self, member: &Url) -> String {
+        let base = member.as_str().trim_end_matches('/');
+        let path = self.path.trim_start_matches('/');
+        format!("{}/{}", base, path)
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MemberHealth {
+    pub member: String,
+    pub probe: String,
+    pub healthy: bool,
+    pub successes: u32,
+    pub failures: u32,
+    pub last_checked: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+// A probe url, along with everything it is probed with, so members shared between endpoints
+// are probed once for each check, protocol and upstream auth. Auth is keyed by its hash, as
+// it can't be compared.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+struct ProbeKey {
+    probe: String,
+    check: HealthCheck,
+    protocol: Protocol,
+    authentication: Option<u64>,
+}
+
+impl ProbeKey {
+    fn new(proxy: &Proxy, check: &HealthCheck, member: &Url) -> ProbeKey {
+        ProbeKey {
+            probe: check.probe_url(member),
+            check: check.clone(),
+            protocol: proxy
+                .config
+                .as_ref()
+                .map(|c| c.protocol)
+                .unwrap_or_default(),
+            authentication: proxy.authentication.as_ref().map(Config::calculate_hash),
+        }
+    }
+}
+
+// A member being probed, with the upstream auth sent along with each probe
+#[derive(Debug, Clone)]
+struct Probe {
+    member: Url,
+    authentication: Option<ServerAuth>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HealthRegistry {
+    members: Arc<RwLock<HashMap<ProbeKey, MemberHealth>>>,
+    tasks: Arc<Mutex<HashMap<ProbeKey, JoinHandle<()>>>>,
+}
+
+impl HealthRegistry {
+    // Members without a health check, or which haven't been probed yet, are treated as healthy
+    pub fn is_healthy(&self, proxy: &Proxy, member: &Url) -> bool {
+        match health_check(proxy) {
+            Some(check) => self
+                .members
+                .read()
+                .unwrap()
+                .get(&ProbeKey::new(proxy, check, member))
+                .map(|m| m.healthy)
+                .unwrap_or(true),
+            None => true,
+        }
+    }
+
+    pub fn members(&self) -> Vec<MemberHealth> {
+        let mut members: Vec<MemberHealth> =
+            self.members.read().unwrap().values().cloned().collect();
+        members.sort_by(|a, b| (&a.member, &a.probe).cmp(&(&b.member, &b.probe)));
+        members
+    }
+
+    // Start probes for any new members in the config, and stop probes for removed members
+    pub fn sync(&self, config_file: &ConfigFile, client: &HttpsClient) {
+        let mut checks = HashMap::new();
+        for proxy in config_file.proxies() {
+            collect_proxy(proxy, &mut checks);
+        }
+
+        let mut tasks = self.tasks.lock().unwrap();
+        let mut stopped = Vec::new();
+        tasks.retain(|key, handle| {
+            let keep = checks.contains_key(key);
+            if !keep {
+                log::debug!("\"Stopping health check for {}\"", key.probe);
+                handle.abort();
+                if let Some(health) = self.members.write().unwrap().remove(key) {
+                    stopped.push(health);
+                }
+            }
+            keep
+        });
+
+        for (key, probe) in checks {
+            if tasks.contains_key(&key) {
+                continue;
+            }
+            log::info!("\"Starting health check for {}\"", key.probe);
+            let handle = tokio::spawn(probe_loop(self.clone(), client.clone(), key.clone(), probe));
+            tasks.insert(key, handle);
+        }
+
+        // Gauges can't be removed, so those of members which are no longer probed are zeroed
+        // until they expire
+        for health in stopped {
+            if !tasks.keys().any(|key| key.probe == health.probe) {
+                metrics::gauge!(
+                    "proxima_upstream_healthy",
+                    0.0,
+                    "member" => health.member,
+                    "probe" => health.probe
+                );
+            }
+        }
+    }
+
+    fn record(&self, key: &ProbeKey, member: &Url, error: Option<String>) {
+        let check = &key.check;
+        let mut members = self.members.write().unwrap();
+        let health = members.entry(key.clone()).or_insert_with(|| MemberHealth {
+            member: member.to_string(),
+            probe: key.probe.clone(),
+            healthy: true,
+            successes: 0,
+            failures: 0,
+            last_checked: 0,
+            last_error: None,
+        });
+
+        health.last_checked = Utc::now().timestamp();
+        match error {
+            None => {
+                health.successes += 1;
+                health.failures = 0;
+                if !health.healthy && health.successes >= check.healthy_threshold {
+                    log::info!("\"Upstream {} is healthy\"", member);
+                    health.healthy = true;
+                }
+            }
+            Some(e) => {
+                health.failures += 1;
+                health.successes = 0;
+                if health.healthy && health.failures >= check.unhealthy_threshold {
+                    log::warn!("\"Upstream {} is unhealthy: {}\"", member, e);
+                    health.healthy = false;
+                }
+                health.last_error = Some(e);
+            }
+        }
+        if health.healthy {
+            health.last_error = None;
+        }
+
+        let value = if health.healthy { 1.0 } else { 0.0 };
+        metrics::gauge!(
+            "proxima_upstream_healthy",
+            value,
+            "member" => member.to_string(),
+            "probe" => key.probe.clone()
+        );
+    }
+}
+
+async fn probe_loop(registry: HealthRegistry, client: HttpsClient, key: ProbeKey, probe: Probe) {
+    loop {
+        let error = probe_once(&client, &key, &probe.authentication).await.err();
+        registry.record(&key, &probe.member, error);
+        tokio::time::sleep(Duration::from_millis(key.check.interval.max(1))).await;
+    }
+}
+
+// Probes are sent like proxied requests, with the endpoint's protocol and upstream auth. Any
+// 2xx or 3xx response within the timeout counts as healthy.
+async fn probe_once(
+    client: &HttpsClient,
+    key: &ProbeKey,
+    authentication: &Option<ServerAuth>,
+) -> Result<(), String> {
+    let uri: Uri = key.probe.parse().map_err(|e: InvalidUri| e.to_string())?;
+    let mut req = Request::builder()
+        .method("GET")
+        .uri(&uri)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    if let Some(authentication) = authentication {
+        authentication
+            .headers(req.headers_mut(), &uri)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    match tokio::time::timeout(
+        Duration::from_millis(key.check.timeout),
+        client.request_with(req, key.protocol),
+    )
+    .await
+    {
+        Ok(Ok(response))
+            if response.status().is_success() || response.status().is_redirection() =>
+        {
+            Ok(())
+        }
+        Ok(Ok(response)) => Err(format!("status {}", response.status().as_u16())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timeout".to_string()),
+    }
+}
+
+fn health_check(proxy: &Proxy) -> Option<&HealthCheck> {
+    match &proxy.url {
+        Urls::UrlFailover(failover) => failover.health_check.as_ref(),
+        Urls::LoadBalance(balance) => balance.health_check.as_ref(),
+        Urls::Url(_) => None,
+    }
+}
+
+fn collect_proxy(proxy: &Proxy, checks: &mut HashMap<ProbeKey, Probe>) {
+    if let Some(check) = health_check(proxy) {
+        for member in proxy.url.members() {
+            let probe = Probe {
+                member: member.clone(),
+                authentication: proxy.authentication.clone(),
+            };
+            checks.insert(ProbeKey::new(proxy, check, member), probe);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(yaml: &str) -> Proxy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const PROXY: &str = "
+url:
+  failover:
+  - http://api-0.api.svc:8080
+  health_check:
+    path: /healthz
+    healthy_threshold: 2
+    unhealthy_threshold: 3
+";
+
+    fn member() -> Url {
+        Url::parse("http://api-0.api.svc:8080").unwrap()
+    }
+
+    fn record(registry: &HealthRegistry, proxy: &Proxy, failed: bool) {
+        let key = ProbeKey::new(proxy, health_check(proxy).unwrap(), &member());
+        let error = failed.then(|| "status 500".to_string());
+        registry.record(&key, &member(), error);
+    }
+
+    #[test]
+    fn thresholds_change_health() {
+        let registry = HealthRegistry::default();
+        let proxy = proxy(PROXY);
+        assert!(registry.is_healthy(&proxy, &member()));
+
+        record(&registry, &proxy, true);
+        record(&registry, &proxy, true);
+        assert!(registry.is_healthy(&proxy, &member()));
+        record(&registry, &proxy, true);
+        assert!(!registry.is_healthy(&proxy, &member()));
+        assert_eq!(
+            registry.members()[0].last_error.as_deref(),
+            Some("status 500")
+        );
+
+        record(&registry, &proxy, false);
+        assert!(!registry.is_healthy(&proxy, &member()));
+        record(&registry, &proxy, false);
+        assert!(registry.is_healthy(&proxy, &member()));
+        assert_eq!(registry.members()[0].last_error, None);
+    }
+
+    #[test]
+    fn successes_reset_failures() {
+        let registry = HealthRegistry::default();
+        let proxy = proxy(PROXY);
+        for failed in [true, true, false, true, true] {
+            record(&registry, &proxy, failed);
+        }
+        assert!(registry.is_healthy(&proxy, &member()));
+        assert_eq!(registry.members()[0].failures, 2);
+    }
+
+    #[test]
+    fn probes_keyed_by_protocol_and_auth() {
+        let registry = HealthRegistry::default();
+        let plain = proxy(PROXY);
+        record(&registry, &plain, true);
+        record(&registry, &plain, true);
+        record(&registry, &plain, true);
+        assert!(!registry.is_healthy(&plain, &member()));
+
+        let http2 = proxy(&format!("{}config:\n  protocol: http2\n", PROXY));
+        let authenticated = proxy(&format!(
+            "{}authentication:\n  basic:\n    username: probe\n    password: secret\n",
+            PROXY
+        ));
+        assert!(registry.is_healthy(&http2, &member()));
+        assert!(registry.is_healthy(&authenticated, &member()));
+    }
+}
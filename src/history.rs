@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::Hash;
+
+use crate::config::{Config, ConfigFile, ConfigMap, Route};
+use crate::error::Error as ProximaError;
+
+// A config that has been served, along with when and why it was swapped in
+#[derive(Debug, Clone)]
+pub struct ConfigVersion {
+    pub hash: u64,
+    pub timestamp: i64,
+    pub source: String,
+    pub config_file: ConfigFile,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VersionSummary {
+    pub hash: String,
+    pub timestamp: i64,
+    pub source: String,
+    pub current: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DiffParams {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub versions: VecDeque<ConfigVersion>,
+}
+
+// Hashes are shown as hex, as javascript clients can't represent every u64
+pub fn display_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+impl History {
+    pub fn push(&mut self, version: ConfigVersion, size: usize) {
+        self.versions.push_back(version);
+        while self.versions.len() > size.max(1) {
+            self.versions.pop_front();
+        }
+    }
+
+    // A rollback adds the version again, so only the newest entry is current, even when
+    // older entries share its hash
+    pub fn summary(&self) -> Vec<VersionSummary> {
+        self.versions
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(index, v)| VersionSummary {
+                hash: display_hash(v.hash),
+                timestamp: v.timestamp,
+                source: v.source.clone(),
+                current: index == 0,
+            })
+            .collect()
+    }
+
+    // Find a version by hash, or the version offset back from the current one
+    pub fn find(&self, hash: Option<&str>, offset: usize) -> Result<&ConfigVersion, ProximaError> {
+        match hash {
+            Some(hash) => self
+                .versions
+                .iter()
+                .rev()
+                .find(|v| display_hash(v.hash) == hash.to_lowercase())
+                .ok_or_else(|| ProximaError::UnknownVersion(hash.to_string())),
+            None => self
+                .versions
+                .iter()
+                .rev()
+                .nth(offset)
+                .ok_or_else(|| ProximaError::UnknownVersion(format!("current~{}", offset))),
+        }
+    }
+
+    // Compare two versions, defaulting to the previous and current configs
+    pub fn diff(&self, params: &DiffParams) -> Result<Value, ProximaError> {
+        let from = self.find(params.from.as_deref(), 1)?;
+        let to = self.find(params.to.as_deref(), 0)?;
+
+        let before = flatten(&from.config_file);
+        let after = flatten(&to.config_file);
+
+        let added: Vec<&String> = after.keys().filter(|k| !before.contains_key(*k)).collect();
+        let removed: Vec<&String> = before.keys().filter(|k| !after.contains_key(*k)).collect();
+        let changed: Vec<Value> = before
+            .iter()
+            .filter_map(|(route, old)| match after.get(route) {
+                Some(new) if new.hash != old.hash => Some(change(route, old, new)),
+                _ => None,
+            })
+            .collect();
+
+        Ok(json!({
+            "from": display_hash(from.hash),
+            "to": display_hash(to.hash),
+            "global": Config::calculate_hash(&from.config_file.global)
+                != Config::calculate_hash(&to.config_file.global),
+            "added": added,
+            "removed": removed,
+            "changed": changed,
+        }))
+    }
+}
+
+// Routes are compared by hash, as credentials and client security are left out when they
+// are serialized
+struct Entry {
+    hash: u64,
+    value: Value,
+}
+
+impl Entry {
+    fn new<T: Hash + Serialize>(item: &T) -> Self {
+        Entry {
+            hash: Config::calculate_hash(item),
+            value: json!(item),
+        }
+    }
+}
+
+// Changes to fields which aren't serialized are marked, rather than shown
+fn change(route: &str, old: &Entry, new: &Entry) -> Value {
+    let mut change = json!({"route": route, "from": old.value, "to": new.value});
+    if old.value == new.value {
+        change["hidden"] = json!("<changed>");
+    }
+    change
+}
+
+// Flatten every endpoint into its full route path, with virtual hosts prefixed by hosts.<host>
+fn flatten(config_file: &ConfigFile) -> BTreeMap<String, Entry> {
+    let mut routes = BTreeMap::new();
+    flatten_routes("", &config_file.routes, &mut routes);
+    for (host, vhost) in &config_file.hosts {
+        let prefix = format!("hosts.{}", host);
+        if vhost.auth.is_some() {
+            routes.insert(format!("{}.auth", prefix), Entry::new(&vhost.auth));
+        }
+        flatten_routes(&prefix, &vhost.routes, &mut routes);
+    }
+    routes
+}
+
+fn flatten_routes(prefix: &str, config_map: &ConfigMap, routes: &mut BTreeMap<String, Entry>) {
+    for (key, route) in config_map {
+        let name = match prefix {
+            "" => key.clone(),
+            _ => format!("{}/{}", prefix, key),
+        };
+        match route {
+            Route::ConfigMap(map) => flatten_routes(&name, map, routes),
+            Route::Endpoint(endpoint) => {
+                routes.insert(name, Entry::new(endpoint));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BEFORE: &str = "
+routes:
+  users:
+    proxy:
+      url: http://users:8080
+  legacy:
+    proxy:
+      url: http://legacy:8080
+  billing:
+    invoices:
+      proxy:
+        url: http://billing:8080
+        authentication:
+          basic:
+            username: billing
+            password: before
+";
+
+    const AFTER: &str = "
+routes:
+  users:
+    proxy:
+      url: http://users-v2:8080
+  orders:
+    proxy:
+      url: http://orders:8080
+  billing:
+    invoices:
+      proxy:
+        url: http://billing:8080
+        authentication:
+          basic:
+            username: billing
+            password: after
+";
+
+    fn version(hash: u64, yaml: &str) -> ConfigVersion {
+        ConfigVersion {
+            hash,
+            timestamp: hash as i64,
+            source: "test".to_string(),
+            config_file: serde_yaml::from_str(yaml).unwrap(),
+        }
+    }
+
+    fn history() -> History {
+        let mut history = History::default();
+        history.push(version(1, BEFORE), 10);
+        history.push(version(2, AFTER), 10);
+        history
+    }
+
+    #[test]
+    fn diff_routes() {
+        let diff = history().diff(&DiffParams::default()).unwrap();
+        assert_eq!(diff["from"], display_hash(1));
+        assert_eq!(diff["to"], display_hash(2));
+        assert_eq!(diff["global"], false);
+        assert_eq!(diff["added"], json!(["orders"]));
+        assert_eq!(diff["removed"], json!(["legacy"]));
+
+        let changed = diff["changed"].as_array().unwrap();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(changed[0]["route"], "billing/invoices");
+        // Credentials aren't shown, only that something changed
+        assert_eq!(changed[0]["hidden"], "<changed>");
+        assert!(!changed[0].to_string().contains("before"));
+        assert_eq!(changed[1]["route"], "users");
+        assert_eq!(changed[1]["to"]["proxy"]["url"], "http://users-v2:8080/");
+        assert!(changed[1].get("hidden").is_none());
+    }
+
+    #[test]
+    fn diff_between_hashes() {
+        let history = history();
+        let params = DiffParams {
+            from: Some(display_hash(2)),
+            to: Some(display_hash(1)),
+        };
+        let diff = history.diff(&params).unwrap();
+        assert_eq!(diff["added"], json!(["legacy"]));
+        assert_eq!(diff["removed"], json!(["orders"]));
+
+        let unknown = DiffParams {
+            from: Some("ffff".to_string()),
+            to: None,
+        };
+        assert!(matches!(
+            history.diff(&unknown),
+            Err(ProximaError::UnknownVersion(_))
+        ));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = history();
+        history.push(version(1, BEFORE), 2);
+        let summary = history.summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].hash, display_hash(1));
+        assert!(summary[0].current);
+        assert!(!summary[1].current);
+    }
+}
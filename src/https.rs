@@ -1,11 +1,20 @@
 use core::time::Duration;
+use hyper::client::connect::{Connected, Connection};
 use hyper::client::HttpConnector;
+use hyper::service::Service;
 use hyper::Body;
 use hyper::Request;
 use hyper::Response;
-use hyper_tls::HttpsConnector;
+use hyper::Uri;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
 use native_tls::{Certificate, TlsConnector};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
 use tokio::sync::RwLock;
 
 use crate::config_global::GlobalConfig;
@@ -14,17 +23,50 @@ use crate::error::Error as ProximaError;
 //pub type Client = hyper::client::Client<HttpsConnector<HttpConnector>, Body>;
 //type BoxResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
 
+// HTTP version used to talk to an upstream
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    // HTTP/1.1 only
+    #[default]
+    Http1,
+    // HTTP/2 only, using prior knowledge (h2c) for http urls, and ALPN h2 for https urls
+    Http2,
+    // HTTP/2 when negotiated through ALPN on https urls, otherwise HTTP/1.1
+    Auto,
+}
+
+#[derive(Debug)]
+pub struct Clients {
+    http1: hyper::client::Client<HttpsConnector<HttpConnector>, Body>,
+    http2: hyper::client::Client<AlpnConnector, Body>,
+    auto: hyper::client::Client<AlpnConnector, Body>,
+}
+
 #[derive(Debug, Clone)]
-pub struct HttpsClient(Arc<RwLock<hyper::client::Client<HttpsConnector<HttpConnector>, Body>>>);
+pub struct HttpsClient(Arc<RwLock<Clients>>);
 
 impl HttpsClient {
     pub async fn request(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
         let locked = self.0.read().await;
-        locked.request(req).await
+        locked.http1.request(req).await
+    }
+
+    pub async fn request_with(
+        &self,
+        req: Request<Body>,
+        protocol: Protocol,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let locked = self.0.read().await;
+        match protocol {
+            Protocol::Http1 => locked.http1.request(req).await,
+            Protocol::Http2 => locked.http2.request(req).await,
+            Protocol::Auto => locked.auto.request(req).await,
+        }
     }
 
     // This should only be ran against a new HttpsClient
-    pub async fn internal(self) -> hyper::client::Client<HttpsConnector<HttpConnector>, Body> {
+    pub async fn internal(self) -> Clients {
         let rwlock = Arc::try_unwrap(self.0).expect("Unable to unwrap Arc");
         rwlock.into_inner()
     }
@@ -124,23 +166,35 @@ impl<'a> ClientBuilder<'a> {
         self
     }
     pub fn build(&mut self) -> Result<HttpsClient, ProximaError> {
-        let tls_connector = match self.config.import_cert {
-            Some(path) => {
-                let cert = &std::fs::read(path).expect("Failed reading in root cert");
-                let import_cert =
-                    Certificate::from_pem(cert).expect("Root cert is not in PEM format");
-                log::info!("Reading in root cert at {}", &path);
-                TlsConnector::builder()
-                    .danger_accept_invalid_hostnames(self.config.accept_invalid_hostnames)
-                    .danger_accept_invalid_certs(self.config.accept_invalid_certs)
-                    .add_root_certificate(import_cert)
-                    .build()?
-            }
-            None => TlsConnector::builder()
-                .danger_accept_invalid_hostnames(self.config.accept_invalid_hostnames)
-                .danger_accept_invalid_certs(self.config.accept_invalid_certs)
-                .build()?,
-        };
+        let http1 = hyper::Client::builder().build::<_, hyper::Body>(self.connector(&[])?);
+        let http2 = hyper::Client::builder()
+            .http2_only(true)
+            .build::<_, hyper::Body>(AlpnConnector(self.connector(&["h2"])?));
+        let auto = hyper::Client::builder()
+            .build::<_, hyper::Body>(AlpnConnector(self.connector(&["h2", "http/1.1"])?));
+
+        Ok(HttpsClient(Arc::new(RwLock::new(Clients {
+            http1,
+            http2,
+            auto,
+        }))))
+    }
+
+    fn connector(&self, alpns: &[&str]) -> Result<HttpsConnector<HttpConnector>, ProximaError> {
+        let mut tls_builder = TlsConnector::builder();
+        tls_builder
+            .danger_accept_invalid_hostnames(self.config.accept_invalid_hostnames)
+            .danger_accept_invalid_certs(self.config.accept_invalid_certs);
+        if let Some(path) = self.config.import_cert {
+            let cert = &std::fs::read(path).expect("Failed reading in root cert");
+            let import_cert = Certificate::from_pem(cert).expect("Root cert is not in PEM format");
+            log::info!("Reading in root cert at {}", &path);
+            tls_builder.add_root_certificate(import_cert);
+        }
+        if !alpns.is_empty() {
+            tls_builder.request_alpns(alpns);
+        }
+        let tls_connector = tls_builder.build()?;
 
         let mut http = hyper::client::HttpConnector::new();
 
@@ -154,8 +208,74 @@ impl<'a> ClientBuilder<'a> {
 
         let https: hyper_tls::HttpsConnector<hyper::client::HttpConnector> =
             hyper_tls::HttpsConnector::from((http, tls_connector.into()));
-        Ok(HttpsClient(Arc::new(RwLock::new(
-            hyper::Client::builder().build::<_, hyper::Body>(https),
-        ))))
+        Ok(https)
+    }
+}
+
+// hyper-tls doesn't tell hyper which protocol was negotiated through ALPN, so this wraps its
+// connector to mark connections where the upstream picked h2
+#[derive(Debug, Clone)]
+pub struct AlpnConnector(HttpsConnector<HttpConnector>);
+
+pub struct AlpnStream(MaybeHttpsStream<TcpStream>);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl Service<Uri> for AlpnConnector {
+    type Response = AlpnStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<AlpnStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.0.call(uri);
+        Box::pin(async move { Ok(AlpnStream(connecting.await?)) })
+    }
+}
+
+impl Connection for AlpnStream {
+    fn connected(&self) -> Connected {
+        match &self.0 {
+            MaybeHttpsStream::Http(stream) => stream.connected(),
+            MaybeHttpsStream::Https(stream) => {
+                let tls = stream.get_ref();
+                let connected = tls.get_ref().get_ref().connected();
+                match tls.negotiated_alpn() {
+                    Ok(Some(protocol)) if protocol == b"h2" => connected.negotiated_h2(),
+                    _ => connected,
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for AlpnStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AlpnStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().0).poll_shutdown(cx)
     }
 }
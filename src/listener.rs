@@ -0,0 +1,160 @@
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::Accept;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+
+use crate::config::Config;
+use crate::proxy_protocol::ProxiedStream;
+use crate::tls::TlsTermination;
+
+// Connections must send any PROXY header and finish the TLS handshake within this time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Connection {
+    Plain(ProxiedStream),
+    Tls(Box<TlsStream<ProxiedStream>>),
+}
+
+// Handlers receive the source address from any PROXY header through ConnectInfo
+impl Connected<&Connection> for SocketAddr {
+    fn connect_info(target: &Connection) -> Self {
+        match target {
+            Connection::Plain(stream) => stream.remote_addr(),
+            Connection::Tls(stream) => stream.get_ref().0.remote_addr(),
+        }
+    }
+}
+
+// Accepts connections for hyper, reading PROXY headers and completing TLS handshakes in
+// their own tasks, so slow or broken clients don't hold up other connections. PROXY headers
+// are required when proxy_protocol is given, and only accepted from its trusted proxies.
+pub struct Acceptor {
+    connections: mpsc::Receiver<Connection>,
+}
+
+impl Acceptor {
+    pub fn new(
+        listener: TcpListener,
+        proxy_protocol: Option<Config>,
+        tls: Option<TlsTermination>,
+    ) -> Self {
+        let (sender, connections) = mpsc::channel(128);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::error!("{{\"error\": \"Unable to accept connection: {}\"}}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                let proxy_protocol = proxy_protocol.clone();
+                let tls = tls.clone();
+                tokio::spawn(async move {
+                    let handshake = handshake(stream, peer, proxy_protocol, tls);
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(connection)) => {
+                            let _ = sender.send(connection).await;
+                        }
+                        Ok(Err(e)) => {
+                            log::warn!("\"Dropping connection from {}: {}\"", peer, e)
+                        }
+                        Err(_) => {
+                            log::warn!("\"Dropping connection from {}: handshake timed out\"", peer)
+                        }
+                    }
+                });
+            }
+        });
+        Acceptor { connections }
+    }
+}
+
+async fn handshake(
+    stream: TcpStream,
+    peer: SocketAddr,
+    proxy_protocol: Option<Config>,
+    tls: Option<TlsTermination>,
+) -> io::Result<Connection> {
+    let stream = match proxy_protocol {
+        Some(config) if !config.trusts(peer.ip()).await => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "PROXY headers are only accepted from trusted proxies",
+            ))
+        }
+        Some(_) => ProxiedStream::decode(stream, peer).await?,
+        None => ProxiedStream::direct(stream, peer),
+    };
+    match tls {
+        Some(tls) => {
+            let acceptor = tls
+                .acceptor()
+                .ok_or_else(|| io::Error::other("no TLS certificates"))?;
+            let stream = acceptor.accept(stream).await?;
+            Ok(Connection::Tls(Box::new(stream)))
+        }
+        None => Ok(Connection::Plain(stream)),
+    }
+}
+
+impl Accept for Acceptor {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.connections.poll_recv(cx).map(|c| c.map(Ok))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
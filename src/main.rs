@@ -10,32 +10,60 @@ use clap::{crate_name, crate_version, Arg, Command};
 use env_logger::{Builder, Target};
 use jemallocator::Jemalloc;
 use log::LevelFilter;
+use std::future::Future;
 use std::io::Write;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::net::TcpListener;
 use tower::limit::ConcurrencyLimitLayer;
 use tower_http::trace::TraceLayer;
 
 mod auth;
+mod balance;
+mod breaker;
 mod cache;
+mod conditional;
 mod config;
 mod config_global;
+mod config_include;
 mod error;
+mod forwarded;
 mod handlers;
+mod header_rules;
+mod health;
+mod history;
 mod https;
+mod listener;
 mod metrics;
 mod path;
+mod proxy_protocol;
+mod reload;
 mod requests;
+mod retry;
+mod rewrite;
+mod route_edit;
+mod secrets;
 mod security;
 mod state;
+mod sticky;
+mod tls;
+mod upgrade;
 mod urls;
+mod validate;
 mod vault;
+mod watch;
 
 use crate::metrics::{setup_metrics_recorder, track_metrics};
 use handlers::{
-    cache_delete, cache_get, config, echo, handler_404, health, mappings_get, metrics, proxy,
-    reload, routes,
+    cache_delete, cache_get, circuits, config, config_diff, config_history, config_rollback, echo,
+    handler_404, health, mappings_get, metrics, proxy, reload, route_delete, route_get,
+    route_patch, route_put, routes, upstreams,
 };
+use listener::Acceptor;
 use state::State;
+use validate::Report;
+
+type Server = Pin<Box<dyn Future<Output = Result<(), hyper::Error>>>>;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
@@ -47,6 +75,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .version(crate_version!())
         .author("Daniel F. <Verticaleap>")
         .about(crate_name!())
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("validate")
+                .about("Validate config file, without starting proxima")
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .env("PROXIMA_CONFIG")
+                        .required(true)
+                        .help("Config file")
+                        .takes_value(true),
+                ),
+        )
         .arg(
             Arg::new("port")
                 .short('p')
@@ -65,6 +107,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .default_value("8081")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("proxy_protocol")
+                .long("proxy_protocol")
+                .env("PROXIMA_PROXY_PROTOCOL")
+                .required(false)
+                .help("Require a PROXY protocol v1 or v2 header on proxy connections")
+                .takes_value(false),
+        )
         .arg(
             Arg::new("config_username")
                 .short('u')
@@ -91,6 +141,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
                 .help("Config file")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("snapshot")
+                .long("snapshot")
+                .env("PROXIMA_SNAPSHOT")
+                .required(false)
+                .help("Save last known good config to file, and start from it if config is unavailable")
+                .takes_value(true),
+        )
         .arg(
             Arg::new("insecure")
                 .long("insecure")
@@ -190,6 +248,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .parse_default_env()
         .init();
 
+    // Validate config and exit, if requested
+    if let Some(("validate", validate_opts)) = opts.subcommand() {
+        let location = validate_opts.value_of("config").unwrap();
+        let report = Report::build(location).await;
+        for warning in &report.warnings {
+            println!("warning: {}", warning);
+        }
+        for issue in &report.issues {
+            println!("{}", issue);
+        }
+        if report.is_valid() {
+            println!("{} is valid", location);
+            return Ok(());
+        } else {
+            println!("{} has {} issue(s)", location, report.issues.len());
+            std::process::exit(1);
+        }
+    }
+
     // Set main listen port
     let port: u16 = opts.value_of("port").unwrap().parse().unwrap_or_else(|_| {
         eprintln!("specified port isn't in a valid range, setting to 8080");
@@ -217,17 +294,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut state = State::basic(opts.clone()).await;
     state.build(opts.clone()).await?;
 
+    // Terminate TLS on both listeners when certificates are configured
+    let tls = match state.config.tls.is_configured() {
+        true if state.config.tls.acceptor().is_none() => {
+            return Err("unable to load TLS certificates".into())
+        }
+        true => Some(state.config.tls.clone()),
+        false => None,
+    };
+    state.tls = tls.is_some();
+    state.config.tls.listening(state.tls);
+
+    // PROXY headers are checked against the trusted proxies of the current config
+    let proxy_protocol = opts
+        .is_present("proxy_protocol")
+        .then(|| state.config.clone());
+
     // Create prometheus handle
     let recorder_handle = setup_metrics_recorder();
 
     // API Routes
     let api = Router::new()
         .route("/config", get(config))
+        .route("/config/history", get(config_history))
+        .route("/config/diff", get(config_diff))
+        .route("/config/rollback/:hash", post(config_rollback))
         .route("/routes", get(routes))
+        .route(
+            "/routes/*path",
+            get(route_get)
+                .put(route_put)
+                .patch(route_patch)
+                .delete(route_delete),
+        )
         .route("/reload", post(reload))
         .route("/cache", get(cache_get).delete(cache_delete))
         .route("/mappings", get(mappings_get))
         .route("/health", get(health))
+        .route("/upstreams", get(upstreams))
+        .route("/upstreams/circuits", get(circuits))
         .route("/echo", post(echo))
         .route("/metrics", get(metrics))
         .layer(TraceLayer::new_for_http())
@@ -250,14 +355,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Create server for main proxy
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     log::info!("\"Proxy listening on {}\"", addr);
-    let server1 =
-        axum::Server::bind(&addr).serve(proxy.into_make_service_with_connect_info::<SocketAddr>());
+    if let Some(config) = &proxy_protocol {
+        log::info!("\"Proxy expecting PROXY protocol headers\"");
+        if config
+            .config_file()
+            .await
+            .global
+            .network
+            .trusted_proxies
+            .is_empty()
+        {
+            log::warn!(
+                "\"No trusted proxies are configured, so all PROXY headers will be rejected\""
+            );
+        }
+    }
+    let proxy = proxy.into_make_service_with_connect_info::<SocketAddr>();
+    let server1: Server = match proxy_protocol.is_some() || tls.is_some() {
+        true => {
+            let acceptor =
+                Acceptor::new(TcpListener::bind(addr).await?, proxy_protocol, tls.clone());
+            Box::pin(axum::Server::builder(acceptor).serve(proxy))
+        }
+        false => Box::pin(axum::Server::bind(&addr).serve(proxy)),
+    };
 
     // Create server for API
     let addr = SocketAddr::from(([0, 0, 0, 0], api_port));
     log::info!("\"API listening on {}\"", addr);
-    let server2 =
-        axum::Server::bind(&addr).serve(api.into_make_service_with_connect_info::<SocketAddr>());
+    let api = api.into_make_service_with_connect_info::<SocketAddr>();
+    let server2: Server = match tls {
+        Some(tls) => {
+            let acceptor = Acceptor::new(TcpListener::bind(addr).await?, None, Some(tls));
+            Box::pin(axum::Server::builder(acceptor).serve(api))
+        }
+        None => Box::pin(axum::Server::bind(&addr).serve(api)),
+    };
 
     tokio::try_join!(server1, server2)?;
 
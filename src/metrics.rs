@@ -1,10 +1,17 @@
+use axum::body::{boxed, Bytes};
 use axum::{http::Request, middleware::Next, response::IntoResponse};
 use core::time::Duration;
 use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::HeaderMap;
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 use metrics_util::MetricKindMask;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Instant;
 
+type Labels = Vec<(&'static str, String)>;
+
 pub fn setup_metrics_recorder() -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] =
         &[0.001, 0.01, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
@@ -34,15 +41,114 @@ pub async fn track_metrics<B: HttpBody>(req: Request<B>, next: Next<B>) -> impl
     let status = response.status().as_u16().to_string();
     let request_transmit = response.body().size_hint().upper().unwrap_or(0) as f64;
 
-    let labels = [
+    let mut labels = vec![
         ("method", method.to_string()),
         ("path", path),
         ("status", status),
     ];
 
-    metrics::increment_counter!("proxima_requests_total", &labels);
-    metrics::increment_gauge!("proxima_requests_receive_bytes", request_receive, &labels);
-    metrics::increment_gauge!("proxima_requests_transmit_bytes", request_transmit, &labels);
-    metrics::histogram!("proxima_requests_duration_seconds", latency, &labels);
-    response
+    if !is_grpc(response.headers()) {
+        record(&labels, latency, request_receive, request_transmit);
+        return response;
+    }
+
+    // Responses without a body carry the grpc status in their headers, otherwise it is
+    // sent in the trailers, and the response is counted once those arrive
+    if let Some(grpc_status) = grpc_status(response.headers()) {
+        labels.push(("grpc_status", grpc_status));
+        record(&labels, latency, request_receive, request_transmit);
+        return response;
+    }
+
+    response.map(|body| {
+        boxed(GrpcBody {
+            inner: body,
+            grpc_status: None,
+            labels,
+            latency,
+            request_receive,
+            request_transmit,
+        })
+    })
+}
+
+fn record(labels: &Labels, latency: f64, request_receive: f64, request_transmit: f64) {
+    metrics::increment_counter!("proxima_requests_total", labels);
+    metrics::increment_gauge!("proxima_requests_receive_bytes", request_receive, labels);
+    metrics::increment_gauge!("proxima_requests_transmit_bytes", request_transmit, labels);
+    metrics::histogram!("proxima_requests_duration_seconds", latency, labels);
+}
+
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("grpc-status")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string())
+}
+
+// Watches a grpc response body for its trailers, and records the response metrics once the
+// body is finished with. Bodies dropped before their trailers arrive get a status of unknown.
+struct GrpcBody {
+    inner: axum::body::BoxBody,
+    grpc_status: Option<String>,
+    labels: Labels,
+    latency: f64,
+    request_receive: f64,
+    request_transmit: f64,
+}
+
+impl HttpBody for GrpcBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let this = self.get_mut();
+        let trailers = Pin::new(&mut this.inner).poll_trailers(cx);
+        if let Poll::Ready(Ok(Some(headers))) = &trailers {
+            this.grpc_status = grpc_status(headers);
+        }
+        trailers
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for GrpcBody {
+    fn drop(&mut self) {
+        let grpc_status = self
+            .grpc_status
+            .take()
+            .unwrap_or_else(|| "unknown".to_string());
+        self.labels.push(("grpc_status", grpc_status));
+        record(
+            &self.labels,
+            self.latency,
+            self.request_receive,
+            self.request_transmit,
+        );
+    }
 }
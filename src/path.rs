@@ -2,7 +2,10 @@ use axum::{
     async_trait,
     extract::{FromRequest, RequestParts},
 };
+use regex::Regex;
 use serde::Serialize;
+use std::cell::Cell;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::sync::Arc;
 use std::sync::RwLock;
@@ -15,6 +18,7 @@ pub struct ProxyPath {
     pub vec: Vec<String>,
     pub count: Arc<RwLock<i32>>,
     pub max: i32,
+    pub params: BTreeMap<String, String>,
 }
 
 impl ProxyPath {
@@ -47,6 +51,7 @@ impl ProxyPath {
                 vec,
                 count: Arc::new(RwLock::new(-1)),
                 max: max as i32,
+                params: BTreeMap::new(),
             }
         } else {
             ProxyPath {
@@ -54,6 +59,7 @@ impl ProxyPath {
                 vec: vec![path.to_string()],
                 count: Arc::new(RwLock::new(-1)),
                 max: 1,
+                params: BTreeMap::new(),
             }
         }
     }
@@ -95,6 +101,219 @@ impl ProxyPath {
         &self.path
     }
 
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+
+    // Route keys are patterns if they span multiple segments, or contain {param} or * segments
+    pub fn is_pattern(key: &str) -> bool {
+        match key {
+            "/" => false,
+            "*" => true,
+            _ if ProxyPath::regex_key(key).is_some() => false,
+            _ => key.contains('/') || ProxyPath::param_name(key).is_some(),
+        }
+    }
+
+    // Route keys prefixed with ~ are regular expressions
+    pub fn regex_key(key: &str) -> Option<&str> {
+        key.strip_prefix('~').filter(|s| !s.is_empty())
+    }
+
+    // Compile a regex key, anchored to begin at the current segment and end on a segment
+    // boundary, so that alternatives are tried until one ends on a boundary. The key is
+    // compiled alone first, so it can't close the group it is wrapped in.
+    pub fn route_regex(pattern: &str) -> Result<Regex, regex::Error> {
+        Regex::new(pattern)?;
+        Regex::new(&format!("^(?:{})(?:/|$)", pattern))
+    }
+
+    fn param_name(segment: &str) -> Option<&str> {
+        segment
+            .strip_prefix('{')
+            .and_then(|s| s.strip_suffix('}'))
+            .filter(|s| !s.is_empty())
+    }
+
+    // Attempt to match a route key pattern, starting at the current segment. If the pattern
+    // matches, move the path forward to the last matched segment, and save any captured params.
+    pub fn capture(&mut self, pattern: &str) -> bool {
+        let start = match self.count() {
+            -1 => 0,
+            _ => self.count() as usize,
+        };
+        let segments: Vec<&str> = pattern.split('/').collect();
+        let end = start + segments.len();
+
+        let candidates = match self.vec.get(start..end) {
+            Some(c) => c,
+            None => return false,
+        };
+
+        let mut captured = BTreeMap::new();
+        for (segment, value) in segments.iter().zip(candidates) {
+            if *segment == "*" {
+                continue;
+            }
+            match ProxyPath::param_name(segment) {
+                Some(name) => {
+                    captured.insert(name.to_string(), value.to_string());
+                }
+                None if segment == value => continue,
+                None => return false,
+            }
+        }
+
+        if self.set_count((end - 1) as i32).is_err() {
+            return false;
+        }
+
+        log::debug!(
+            "Matched {} against {}, with params {:?}",
+            pattern,
+            self.path(),
+            &captured
+        );
+        self.params.extend(captured);
+        true
+    }
+
+    // Attempt to match a regex compiled by route_regex against the remainder of the path,
+    // starting at the current segment
+    pub fn capture_regex(&mut self, regex: &Regex) -> bool {
+        let start = match self.count() {
+            -1 => 0,
+            _ => self.count() as usize,
+        };
+        let remaining = match self.vec.get(start..) {
+            Some(r) => r.join("/"),
+            None => return false,
+        };
+
+        let captures = match regex.captures(&remaining) {
+            Some(c) => c,
+            None => return false,
+        };
+
+        // The match includes the boundary, which isn't part of the matched segments
+        let matched = captures.get(0).expect("regex match is missing group 0");
+        let matched = matched
+            .as_str()
+            .strip_suffix('/')
+            .unwrap_or(matched.as_str());
+        if matched.is_empty() {
+            log::debug!("{} matched no segments of {}", regex, remaining);
+            return false;
+        }
+
+        let end = start + matched.matches('/').count();
+        if self.set_count(end as i32).is_err() {
+            return false;
+        }
+
+        for name in regex.capture_names().flatten() {
+            if let Some(value) = captures.name(name) {
+                self.params
+                    .insert(name.to_string(), value.as_str().to_string());
+            }
+        }
+
+        log::debug!(
+            "Matched regex {} against {}, with params {:?}",
+            regex,
+            self.path(),
+            &self.params
+        );
+        true
+    }
+
+    // Replace any {param} placeholders, including those that have been percent encoded
+    // by a Url, with the values captured from the path
+    pub fn interpolate(&self, template: &str) -> String {
+        self.interpolate_with(template, |_| None)
+    }
+
+    // Replace placeholders with captured params, falling back to variables for other names.
+    // The template is read once, so values containing placeholders are left as they are.
+    pub fn interpolate_with(
+        &self,
+        template: &str,
+        variables: impl Fn(&str) -> Option<String>,
+    ) -> String {
+        self.render(template, &variables, |value| value.to_string())
+    }
+
+    // Replace placeholders in a url, percent encoding each param for the part of the url it
+    // is substituted into, so that params can't add path segments or query params that weren't
+    // in the request. Params in the authority can't be encoded, so only unreserved characters
+    // are accepted there, and the authority can't be changed.
+    pub fn interpolate_url(&self, url: &str) -> Result<String, ProximaError> {
+        let authority_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+        let authority_end = url[authority_start..]
+            .find(['/', '?', '#'])
+            .map(|i| i + authority_start)
+            .unwrap_or(url.len());
+        let path_end = url[authority_end..]
+            .find(['?', '#'])
+            .map(|i| i + authority_end)
+            .unwrap_or(url.len());
+
+        let none = |_: &str| None;
+        let unreserved = Cell::new(true);
+        let authority = self.render(&url[authority_start..authority_end], &none, |v| {
+            if !v.bytes().all(is_unreserved) {
+                unreserved.set(false);
+            }
+            v.to_string()
+        });
+        if !unreserved.get() {
+            log::debug!("\"Param for the authority of {} is not a hostname\"", url);
+            return Err(ProximaError::UnknownProxy);
+        }
+
+        let mut output = url[..authority_start].to_string();
+        output.push_str(&authority);
+        output.push_str(&self.interpolate_path(&url[authority_end..path_end]));
+        output.push_str(&self.render(&url[path_end..], &none, |v| encode(v, b"!$'()*,;:@/?")));
+        Ok(output)
+    }
+
+    // Replace placeholders in a url path, percent encoding each param for a path
+    pub fn interpolate_path(&self, path: &str) -> String {
+        self.render(path, &|_| None, |v| encode(v, b"!$&'()*+,;=:@/"))
+    }
+
+    fn render(
+        &self,
+        template: &str,
+        variables: &dyn Fn(&str) -> Option<String>,
+        encode: impl Fn(&str) -> String,
+    ) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            let placeholder = [("{", "}"), ("%7B", "%7D")]
+                .iter()
+                .find_map(|(open, close)| {
+                    let inner = rest.strip_prefix(open)?;
+                    let name = &inner[..inner.find(close)?];
+                    let value = self.params.get(name).cloned().or_else(|| variables(name))?;
+                    Some((value, open.len() + name.len() + close.len()))
+                });
+            match placeholder {
+                Some((value, length)) => {
+                    output.push_str(&encode(&value));
+                    rest = &rest[length..];
+                }
+                None => {
+                    output.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        output
+    }
+
     //    pub fn prefix(&self) -> String {
     //        if self.count() == -1 {
     //            return "".to_string()
@@ -193,6 +412,29 @@ impl ProxyPath {
     }
 }
 
+fn is_unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~".contains(&byte)
+}
+
+// Percent encode characters which aren't unreserved or allowed. Params taken from the
+// request path are already percent encoded, so existing escapes are kept.
+fn encode(value: &str, allowed: &[u8]) -> String {
+    let bytes = value.as_bytes();
+    let mut output = String::with_capacity(value.len());
+    for (i, &byte) in bytes.iter().enumerate() {
+        let escape = byte == b'%'
+            && bytes
+                .get(i + 1..i + 3)
+                .is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        if is_unreserved(byte) || allowed.contains(&byte) || escape {
+            output.push(byte as char);
+        } else {
+            output.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    output
+}
+
 #[async_trait]
 impl<B> FromRequest<B> for ProxyPath
 where
@@ -205,3 +447,135 @@ where
         Ok(ProxyPath::new(uri.path()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str, params: &[(&str, &str)]) -> ProxyPath {
+        let mut path = ProxyPath::new(path);
+        for (name, value) in params {
+            path.params.insert(name.to_string(), value.to_string());
+        }
+        path
+    }
+
+    #[test]
+    fn capture_params() {
+        let mut path = ProxyPath::new("/users/42/orders/7");
+        assert!(path.capture("users/{id}"));
+        assert_eq!(path.count(), 1);
+        assert_eq!(path.params()["id"], "42");
+
+        path.next().unwrap();
+        assert!(path.capture("orders/{order}"));
+        assert_eq!(path.params()["order"], "7");
+        assert_eq!(path.suffix(), "");
+    }
+
+    #[test]
+    fn capture_wildcard() {
+        let mut path = ProxyPath::new("/files/a/b");
+        assert!(path.capture("files/*"));
+        assert!(path.params().is_empty());
+        assert_eq!(path.suffix(), "b");
+    }
+
+    #[test]
+    fn capture_mismatch_keeps_state() {
+        let mut path = ProxyPath::new("/users/42");
+        assert!(!path.capture("accounts/{id}"));
+        assert!(!path.capture("users/{id}/orders"));
+        assert_eq!(path.count(), -1);
+        assert!(path.params().is_empty());
+    }
+
+    #[test]
+    fn capture_regex_on_segment_boundary() {
+        let regex = ProxyPath::route_regex(r"v(?P<version>\d+)").unwrap();
+        let mut path = ProxyPath::new("/v2/items");
+        assert!(path.capture_regex(&regex));
+        assert_eq!(path.params()["version"], "2");
+        assert_eq!(path.count(), 0);
+
+        assert!(!ProxyPath::new("/v2beta/items").capture_regex(&regex));
+        assert!(!ProxyPath::new("/api/v2").capture_regex(&regex));
+    }
+
+    #[test]
+    fn capture_regex_tries_alternatives() {
+        let regex = ProxyPath::route_regex(r"v\d|v\d+").unwrap();
+        let mut path = ProxyPath::new("/v12/x");
+        assert!(path.capture_regex(&regex));
+        assert_eq!(path.count(), 0);
+        assert_eq!(path.suffix(), "x");
+
+        let regex = ProxyPath::route_regex(r"(?P<rest>a/.*)").unwrap();
+        let mut path = ProxyPath::new("/a/b/c");
+        assert!(path.capture_regex(&regex));
+        assert_eq!(path.params()["rest"], "a/b/c");
+        assert_eq!(path.suffix(), "");
+
+        let empty = ProxyPath::route_regex("x?").unwrap();
+        assert!(!ProxyPath::new("/y").capture_regex(&empty));
+        assert!(ProxyPath::route_regex("a)|(b").is_err());
+    }
+
+    #[test]
+    fn interpolate_params() {
+        let path = path("/", &[("id", "42"), ("name", "a b")]);
+        assert_eq!(path.interpolate("/users/{id}/{name}"), "/users/42/a b");
+        assert_eq!(
+            path.interpolate_url("/users/{id}/{name}").unwrap(),
+            "/users/42/a%20b"
+        );
+        assert_eq!(path.interpolate("/users/%7Bid%7D"), "/users/42");
+        assert_eq!(path.interpolate("/{unknown}/{id"), "/{unknown}/{id");
+    }
+
+    #[test]
+    fn interpolate_url_encodes_for_position() {
+        let path = path(
+            "/",
+            &[("id", "a b?c#d/%2F"), ("q", "x&y=z+%41"), ("t", "acme")],
+        );
+        assert_eq!(
+            path.interpolate_url("http://{t}.svc:8080/api/%7Bid%7D?tenant=%7Bq%7D&a=1")
+                .unwrap(),
+            "http://acme.svc:8080/api/a%20b%3Fc%23d/%2F?tenant=x%26y%3Dz%2B%41&a=1"
+        );
+    }
+
+    #[test]
+    fn interpolate_url_keeps_authority() {
+        let url = "http://{tenant}.svc:8080/api";
+        for tenant in [
+            "evil.com@x",
+            "evil.com:81",
+            "evil.com/x",
+            "evil.com#",
+            "a%40b",
+        ] {
+            let path = path("/", &[("tenant", tenant)]);
+            assert!(path.interpolate_url(url).is_err(), "{}", tenant);
+        }
+
+        let path = path("/", &[("tenant", "acme-1")]);
+        let uri: hyper::Uri = path.interpolate_url(url).unwrap().parse().unwrap();
+        assert_eq!(uri.host(), Some("acme-1.svc"));
+        assert_eq!(uri.port_u16(), Some(8080));
+    }
+
+    #[test]
+    fn interpolate_single_pass() {
+        let nested = path("/", &[("a", "{b}"), ("b", "secret")]);
+        assert_eq!(nested.interpolate("{a}-{b}"), "{b}-secret");
+
+        let variable = path("/", &[("id", "{client_ip}")]);
+        let rendered = variable.interpolate_with("{id} {client_ip}", |name| match name {
+            "client_ip" => Some("192.0.2.1".to_string()),
+            _ => None,
+        });
+        assert_eq!(rendered, "{client_ip} 192.0.2.1");
+    }
+}
@@ -0,0 +1,289 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+// Longest v1 header, including the trailing CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// Connections with their PROXY header decoded. Any bytes read past the header are
+// replayed before reading from the socket again.
+pub struct ProxiedStream {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    remote_addr: SocketAddr,
+}
+
+impl ProxiedStream {
+    // Connections to listeners without PROXY protocol
+    pub fn direct(stream: TcpStream, remote_addr: SocketAddr) -> Self {
+        ProxiedStream {
+            stream,
+            buffer: Vec::new(),
+            remote_addr,
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    // Read the v1 or v2 header from the start of the connection. LOCAL connections, such
+    // as load balancer health checks, and unknown address families keep the peer address.
+    pub async fn decode(mut stream: TcpStream, peer: SocketAddr) -> io::Result<Self> {
+        let mut buffer = Vec::with_capacity(V1_MAX_LENGTH);
+        let (length, source) = loop {
+            if stream.read_buf(&mut buffer).await? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before PROXY header",
+                ));
+            }
+            if let Some(header) = parse(&buffer)? {
+                break header;
+            }
+        };
+        let remote_addr = source.unwrap_or(peer);
+        log::debug!("\"PROXY header from {} gave source {}\"", peer, remote_addr);
+        buffer.drain(..length);
+        Ok(ProxiedStream {
+            stream,
+            buffer,
+            remote_addr,
+        })
+    }
+}
+
+// Returns the header length and source address once a complete header has been read
+fn parse(buffer: &[u8]) -> io::Result<Option<(usize, Option<SocketAddr>)>> {
+    let prefix = buffer.len().min(V2_SIGNATURE.len());
+    if buffer[..prefix] == V2_SIGNATURE[..prefix] {
+        return parse_v2(buffer);
+    }
+    if !b"PROXY ".starts_with(&buffer[..buffer.len().min(6)]) {
+        return Err(invalid("missing PROXY header"));
+    }
+    parse_v1(buffer)
+}
+
+// PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n
+fn parse_v1(buffer: &[u8]) -> io::Result<Option<(usize, Option<SocketAddr>)>> {
+    let header = &buffer[..buffer.len().min(V1_MAX_LENGTH)];
+    let end = match header.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buffer.len() >= V1_MAX_LENGTH => return Err(invalid("PROXY v1 header too long")),
+        None => return Ok(None),
+    };
+    let line =
+        std::str::from_utf8(&buffer[..end]).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let source = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, port, destination_port] => {
+            let (ip, destination) = match (source.parse::<IpAddr>(), destination.parse::<IpAddr>())
+            {
+                (Ok(ip), Ok(destination)) => (ip, destination),
+                _ => return Err(invalid("invalid PROXY v1 address")),
+            };
+            let ipv4 = *family == "TCP4";
+            if ip.is_ipv4() != ipv4 || destination.is_ipv4() != ipv4 {
+                return Err(invalid("PROXY v1 address does not match its family"));
+            }
+            let port = match (port.parse::<u16>(), destination_port.parse::<u16>()) {
+                (Ok(port), Ok(_)) => port,
+                _ => return Err(invalid("invalid PROXY v1 port")),
+            };
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(invalid("invalid PROXY v1 header")),
+    };
+    Ok(Some((end + 2, source)))
+}
+
+// 12 byte signature, version and command, family and protocol, then a big endian length
+// for the addresses which follow
+fn parse_v2(buffer: &[u8]) -> io::Result<Option<(usize, Option<SocketAddr>)>> {
+    if buffer.len() < 16 {
+        return Ok(None);
+    }
+    let length = 16 + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if buffer.len() < length {
+        return Ok(None);
+    }
+    if buffer[12] >> 4 != 2 {
+        return Err(invalid("invalid PROXY v2 version"));
+    }
+    let addresses = &buffer[16..length];
+    let source = match (buffer[12] & 0x0f, buffer[13] >> 4) {
+        // LOCAL
+        (0, _) => None,
+        // PROXY over IPv4
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(IpAddr::V4(ip), port))
+        }
+        // PROXY over IPv6
+        (1, 2) if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        // Unix sockets and unspecified families
+        (1, _) => None,
+        _ => return Err(invalid("invalid PROXY v2 command")),
+    };
+    Ok(Some((length, source)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffer.is_empty() {
+            let count = self.buffer.len().min(buf.remaining());
+            buf.put_slice(&self.buffer[..count]);
+            self.buffer.drain(..count);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let buffer = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (length, source) = parse(buffer).unwrap().unwrap();
+        assert_eq!(length, 45);
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let buffer = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (_, source) = parse(buffer).unwrap().unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v1_unknown_keeps_peer() {
+        let buffer = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(parse(buffer).unwrap(), Some((buffer.len(), None)));
+    }
+
+    #[test]
+    fn v1_family_mismatch() {
+        assert!(parse(b"PROXY TCP4 2001:db8::1 198.51.100.1 56324 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP6 192.0.2.1 2001:db8::2 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_invalid() {
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 70000 443\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn v1_truncated() {
+        assert_eq!(parse(b"PROX").unwrap(), None);
+        assert_eq!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1").unwrap(), None);
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let mut buffer = b"PROXY UNKNOWN ".to_vec();
+        buffer.resize(V1_MAX_LENGTH, b'a');
+        assert!(parse(&buffer).is_err());
+
+        // The line ending must be within the longest header, even if more has been read
+        buffer.extend_from_slice(b"\r\n");
+        assert!(parse(&buffer).is_err());
+    }
+
+    #[test]
+    fn v2_ipv4() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let mut buffer = v2(1, 1, &addresses);
+        buffer.extend_from_slice(b"GET /");
+        let (length, source) = parse(&buffer).unwrap().unwrap();
+        assert_eq!(length, 28);
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let (_, source) = parse(&v2(1, 2, &addresses)).unwrap().unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[test]
+    fn v2_local_keeps_peer() {
+        assert_eq!(parse(&v2(0, 0, &[])).unwrap(), Some((16, None)));
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let buffer = v2(
+            1,
+            1,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        for end in [5, 12, 15, 20] {
+            assert_eq!(parse(&buffer[..end]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn v2_invalid() {
+        let mut buffer = v2(2, 1, &[]);
+        assert!(parse(&buffer).is_err());
+        buffer[12] = 0x11;
+        assert!(parse(&buffer).is_err());
+    }
+}
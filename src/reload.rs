@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::watch::FileWatcher;
+
+// Start background reloads of config, when the config or files it reads change, or on SIGHUP
+pub fn spawn(config: &Config) {
+    if url::Url::parse(&config.location).is_err() {
+        let me = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch(me).await {
+                log::error!("\"Error watching config file: {}\"", e);
+            }
+        });
+    } else {
+        log::debug!("\"Config location is a url, relying on reload interval\"");
+    }
+
+    #[cfg(unix)]
+    {
+        let me = config.clone();
+        tokio::spawn(async move { hangup(me).await });
+    }
+}
+
+// Keep retrying the config source while serving from the snapshot, even if no requests
+// come in to trigger a renewal
+pub fn retry(config: &Config) {
+    let mut me = config.clone();
+    tokio::spawn(async move {
+        while me.serving_snapshot().await {
+            let interval = me.config_file().await.global.reload.interval.value();
+            tokio::time::sleep(Duration::from_secs(interval.max(1) as u64)).await;
+
+            if me.serving_snapshot().await {
+                reload(&mut me, "snapshot retry").await;
+            }
+        }
+    });
+}
+
+async fn reload(config: &mut Config, reason: &str) {
+    log::info!("\"Reloading config, triggered by {}\"", reason);
+    metrics::increment_counter!("proxima_config_renew_attempts_total");
+    if let Err(e) = config.update().await {
+        log::error!("Error updating config: {}", e);
+        metrics::increment_counter!("proxima_config_renew_failures_total");
+    }
+}
+
+// Watch the config along with any files it includes or references as secrets
+async fn watch(mut config: Config) -> notify::Result<()> {
+    let (mut watcher, mut changes) = FileWatcher::new("config")?;
+    watcher.watch(watched_paths(&config).await);
+
+    while changes.next().await.is_some() {
+        reload(&mut config, "file change").await;
+        watcher.watch(watched_paths(&config).await);
+    }
+
+    Ok(())
+}
+
+async fn watched_paths(config: &Config) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(&config.location)];
+    if let Some(source) = &*config.source.read().await {
+        paths.extend(source.includes());
+        paths.extend(source.secret_files());
+    }
+    paths
+}
+
+#[cfg(unix)]
+async fn hangup(mut config: Config) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut stream = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("\"Unable to listen for SIGHUP: {}\"", e);
+            return;
+        }
+    };
+
+    while stream.recv().await.is_some() {
+        reload(&mut config, "SIGHUP").await;
+    }
+}
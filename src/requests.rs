@@ -2,51 +2,82 @@ use axum::{
     http::uri::Uri,
     http::{Request, Response},
 };
-use hyper::header::HeaderValue;
-use hyper::{Body, HeaderMap, Method};
+use hyper::body::{Bytes, HttpBody, Sender};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, HOST, SET_COOKIE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, HeaderMap, Method, StatusCode};
+use std::net::SocketAddr;
 use std::time::Duration;
 use url::Url;
 
+use crate::balance::Outstanding;
+use crate::breaker::{BreakerRegistry, CircuitBreaker};
 use crate::config::Proxy;
 use crate::error::Error as ProximaError;
+use crate::forwarded::Hop;
+use crate::header_rules::HeaderContext;
+use crate::health::HealthRegistry;
 use crate::https::HttpsClient;
 use crate::path::ProxyPath;
+use crate::retry::RetryPolicy;
+use crate::upgrade;
 use crate::urls::Urls;
 
 pub struct ProxyRequest {
     pub client: HttpsClient,
+    pub health: HealthRegistry,
+    pub breakers: BreakerRegistry,
     pub endpoint: Proxy,
     pub method: Method,
     pub path: ProxyPath,
     pub body: Body,
     pub request_headers: HeaderMap,
     pub query: Option<String>,
+    pub client_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    // Whether the peer is a trusted proxy, whose forwarding headers are kept
+    pub trusted_peer: bool,
+    pub host: Option<String>,
+    pub proto: &'static str,
+    pub upgrade: Option<OnUpgrade>,
+    pub trace_id: String,
+    pub identity: Option<String>,
 }
 
 // Default endpoint connection timeout of 60 seconds
 const TIMEOUT_DEFAULT: u64 = 60000;
 
+// Default idle timeout for upgraded connections of 5 minutes
+const IDLE_TIMEOUT_DEFAULT: u64 = 300000;
+
 impl ProxyRequest {
     pub async fn single(
-        mut self,
+        &self,
         url: &Url,
-        queries: Option<String>,
+        queries: &Option<String>,
+        body: Body,
     ) -> Result<Response<Body>, ProximaError> {
+        let suffix = match &self.endpoint.rewrite {
+            Some(rewrite) => rewrite.apply(&self.path),
+            None => self.path.suffix(),
+        };
+
         // This needs to be done as urls with paths do not end with a forward slash,
         // but urls with no paths do
-        let seperator = match (url.path(), self.path.suffix().as_str()) {
+        let seperator = match (url.path(), suffix.as_str()) {
             ("/", "") => "",
             ("/", _) => "",
             (_, "") => "",
             (_, _) => "/",
         };
 
+        // Fill in any params captured from the route key
         let host_and_path = format!(
             "{}{}{}{}",
-            url,
+            self.path.interpolate_url(url.as_str())?,
             &seperator,
-            self.path.suffix(),
-            queries.unwrap_or_else(|| "".to_string())
+            suffix,
+            queries.as_deref().unwrap_or("")
         );
 
         let uri = match Uri::try_from(host_and_path) {
@@ -62,35 +93,63 @@ impl ProxyRequest {
         let mut req = Request::builder()
             .method(&self.method)
             .uri(&uri)
-            .body(self.body)
+            .body(body)
             .expect("request builder");
 
+        // Headers are copied, as a retried request starts from the client's headers again
+        let mut request_headers = self.request_headers.clone();
+
+        // Add this hop to the forwarding headers, before the client HOST header is removed
+        let forwarded_headers = self
+            .endpoint
+            .config
+            .as_ref()
+            .map(|c| c.forwarded_headers)
+            .unwrap_or_default();
+        let host = self
+            .request_headers
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or(self.host.as_deref());
+        let hop = Hop {
+            peer: self.peer_addr.ip(),
+            client: self.client_addr.ip(),
+            trusted: self.trusted_peer,
+            proto: self.proto,
+            host,
+        };
+        forwarded_headers.apply(&mut request_headers, &hop)?;
+
         // Apply changes to headers based on config
-        if let Some(config) = self.endpoint.config {
+        if let Some(config) = &self.endpoint.config {
             if !config.preserve_host_header {
                 log::debug!("\"Removing client HOST/USER_AGENT headers\"");
                 // Remove HOST and USER_AGENT headers
-                self.request_headers.remove(hyper::header::HOST);
-                self.request_headers.remove(hyper::header::USER_AGENT);
+                request_headers.remove(hyper::header::HOST);
+                request_headers.remove(hyper::header::USER_AGENT);
             };
         } else {
             // Apply all default changes to headers
             // Remove HOST and USER_AGENT headers
             log::debug!("\"Removing client HOST/USER_AGENT headers\"");
-            self.request_headers.remove(hyper::header::HOST);
-            self.request_headers.remove(hyper::header::USER_AGENT);
+            request_headers.remove(hyper::header::HOST);
+            request_headers.remove(hyper::header::USER_AGENT);
         }
 
         // Add x-forwarded-prefix
         let header = HeaderValue::from_str(self.path.path()).unwrap();
-        self.request_headers.insert("x-forwarded-prefix", header);
+        request_headers.insert("x-forwarded-prefix", header);
+
+        if let Some(rules) = &self.endpoint.request_headers {
+            rules.apply(&mut request_headers, &self.header_context());
+        }
 
         // Append to request the headers passed by client
         let headers = req.headers_mut();
-        headers.extend(self.request_headers.clone());
+        headers.extend(request_headers);
 
         // Added Basic Auth if username/password exist
-        if let Some(authentication) = self.endpoint.authentication {
+        if let Some(authentication) = &self.endpoint.authentication {
             authentication.headers(headers, &uri).await?;
         }
 
@@ -99,7 +158,19 @@ impl ProxyRequest {
             None => TIMEOUT_DEFAULT,
         };
 
-        match tokio::time::timeout(Duration::from_millis(timeout), self.client.request(req)).await {
+        let protocol = self
+            .endpoint
+            .config
+            .as_ref()
+            .map(|c| c.protocol)
+            .unwrap_or_default();
+
+        match tokio::time::timeout(
+            Duration::from_millis(timeout),
+            self.client.request_with(req, protocol),
+        )
+        .await
+        {
             Ok(result) => match result {
                 Ok(response) => Ok(response),
                 Err(e) => {
@@ -111,20 +182,132 @@ impl ProxyRequest {
         }
     }
 
-    pub async fn go(self) -> Result<Response<Body>, ProximaError> {
+    pub async fn go(mut self) -> Result<Response<Body>, ProximaError> {
+        let upgrade = self.upgrade.take();
+        let mut result = self.forward().await;
+
+        if let (Some(rules), Ok(response)) = (&self.endpoint.response_headers, &mut result) {
+            rules.apply(response.headers_mut(), &self.header_context());
+        }
+
+        let outstanding = match &mut result {
+            Ok(response) => response.extensions_mut().remove::<Outstanding>(),
+            Err(_) => None,
+        };
+
+        // Splice the client and upstream connections once the upstream agrees to switch protocols
+        if let (Some(client), Ok(response)) = (upgrade, &mut result) {
+            if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                let idle_timeout = self
+                    .endpoint
+                    .config
+                    .as_ref()
+                    .and_then(|c| c.idle_timeout)
+                    .unwrap_or(IDLE_TIMEOUT_DEFAULT);
+                upgrade::tunnel(
+                    client,
+                    response,
+                    Duration::from_millis(idle_timeout),
+                    self.path.path().to_string(),
+                    outstanding,
+                );
+                return result;
+            }
+        }
+
+        if let (Some(outstanding), Ok(response)) = (outstanding, &mut result) {
+            let body = std::mem::take(response.body_mut());
+            *response.body_mut() = hold(body, outstanding);
+        }
+        result
+    }
+
+    fn header_context(&self) -> HeaderContext<'_> {
+        HeaderContext {
+            path: &self.path,
+            client_addr: &self.client_addr,
+            trace_id: &self.trace_id,
+            identity: self.identity.as_deref(),
+        }
+    }
+
+    async fn forward(&mut self) -> Result<Response<Body>, ProximaError> {
         // Prepare queries for appending
         let queries = self.query.as_ref().map(|q| format!("?{}", q));
+        let body = std::mem::take(&mut self.body);
+
+        let policy = match &self.endpoint.retry {
+            Some(policy) if policy.attempts > 1 && policy.allows_method(&self.method) => {
+                policy.clone()
+            }
+            _ => return self.attempt(&queries, body).await,
+        };
+
+        // Bodies are buffered so they can be replayed, up to the policy's max_body
+        if self.oversized(&policy) {
+            log::debug!("\"Request body is too large to replay, skipping retries\"");
+            return self.attempt(&queries, body).await;
+        }
+        let (body, trailers) = match buffer(body, policy.max_body).await? {
+            Buffered::Complete(body, trailers) => (body, trailers),
+            Buffered::Partial(body) => {
+                log::debug!("\"Request body is too large to replay, skipping retries\"");
+                return self.attempt(&queries, body).await;
+            }
+        };
+
+        policy.deposit();
+        let mut attempt = 1;
+        loop {
+            let result = self.attempt(&queries, replay(&body, &trailers)).await;
+            if attempt >= policy.attempts || !policy.should_retry(&result) {
+                return result;
+            }
+            if !policy.withdraw() {
+                log::warn!("\"Retry budget exhausted for {}\"", self.path.path());
+                return result;
+            }
+
+            let delay = policy.delay(attempt);
+            log::info!(
+                "\"Retrying {} after attempt {} of {}, waiting {}ms\"",
+                self.path.path(),
+                attempt,
+                policy.attempts,
+                delay.as_millis()
+            );
+            metrics::increment_counter!("proxima_upstream_retries_total");
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    // Bodies which declare a length over max_body are sent without reading any of them
+    fn oversized(&self, policy: &RetryPolicy) -> bool {
+        self.request_headers
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok())
+            .map(|l| l > policy.max_body)
+            .unwrap_or(false)
+    }
 
+    async fn attempt(
+        &self,
+        queries: &Option<String>,
+        body: Body,
+    ) -> Result<Response<Body>, ProximaError> {
         // Tried improving this, may take some more work
-        match self.endpoint.url.clone() {
+        match &self.endpoint.url {
             Urls::Url(u) => {
                 log::debug!("Got a single url");
-                self.single(&u, queries).await
+                self.send(u, queries, body).await
             }
             Urls::UrlFailover(urlfailover) => {
                 log::debug!("Got a failover url");
-                let url = urlfailover.current();
-                match self.single(url, queries).await {
+                let usable = self.usable(urlfailover.urls())?;
+                let url = urlfailover.healthy(usable);
+                match self.send(url, queries, body).await {
                     Ok(response) => Ok(response),
                     Err(e) => {
                         log::error!("Error connecting to member, failing over member");
@@ -133,6 +316,159 @@ impl ProxyRequest {
                     }
                 }
             }
+            Urls::LoadBalance(balance) => {
+                log::debug!("Got a load balanced url");
+                if balance.is_empty() {
+                    return Err(ProximaError::UnknownProxy);
+                }
+                let usable = self.usable(balance.members.iter().map(|m| m.url()).collect())?;
+                let (url, outstanding) =
+                    balance.select(usable, &self.request_headers, &self.client_addr);
+                let mut result = self.send(url, queries, body).await;
+
+                if let Ok(response) = &mut result {
+                    // Pin the client to the member it was sent to
+                    if let Some(affinity) = &balance.affinity {
+                        let secure = self.proto == "https";
+                        if let Some(cookie) =
+                            affinity.set_cookie(&self.request_headers, url, secure)
+                        {
+                            response.headers_mut().append(SET_COOKIE, cookie);
+                        }
+                    }
+
+                    // The member counts as outstanding until the response has been sent, or
+                    // its upgraded connection closes
+                    response.extensions_mut().insert(outstanding);
+                }
+                result
+            }
+        }
+    }
+
+    // Members with an open circuit are never used, and fail fast when every circuit is open.
+    // Of the rest, healthy members are preferred, falling back to all of them if none are.
+    fn usable<'a>(
+        &'a self,
+        members: Vec<&Url>,
+    ) -> Result<impl Fn(&Url) -> bool + 'a, ProximaError> {
+        let breaker = &self.endpoint.circuit_breaker;
+        let available: Vec<&Url> = members
+            .into_iter()
+            .filter(|u| self.breakers.available(breaker, u))
+            .collect();
+        if available.is_empty() {
+            log::warn!("\"Every circuit is open for {}\"", self.path.path());
+            return Err(ProximaError::CircuitOpen);
+        }
+        let any_healthy = available
+            .iter()
+            .any(|u| self.health.is_healthy(&self.endpoint, u));
+
+        Ok(move |u: &Url| {
+            self.breakers.available(breaker, u)
+                && (!any_healthy || self.health.is_healthy(&self.endpoint, u))
+        })
+    }
+
+    // Send the request to a member, recording the outcome against its circuit. Members picked
+    // while available may have been taken for a probe by another request since.
+    async fn send(
+        &self,
+        url: &Url,
+        queries: &Option<String>,
+        body: Body,
+    ) -> Result<Response<Body>, ProximaError> {
+        let breaker = &self.endpoint.circuit_breaker;
+        if !self.breakers.try_begin(breaker, url) {
+            log::warn!("\"Circuit is open for {}\"", url);
+            return Err(ProximaError::CircuitOpen);
+        }
+        let result = self.single(url, queries, body).await;
+        self.breakers
+            .record(breaker, url, CircuitBreaker::failed(&result));
+        result
+    }
+}
+
+enum Buffered {
+    Complete(Bytes, Option<HeaderMap>),
+    // The part already read, followed by the rest of the stream
+    Partial(Body),
+}
+
+// Read at most limit bytes of a body, as bodies without a length may be of any size
+async fn buffer(mut body: Body, limit: u64) -> Result<Buffered, ProximaError> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut length = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        length += chunk.len() as u64;
+        chunks.push(chunk);
+        if length > limit {
+            let (mut sender, partial) = Body::channel();
+            tokio::spawn(async move {
+                for chunk in chunks {
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+                pipe(body, sender).await
+            });
+            return Ok(Buffered::Partial(partial));
+        }
+    }
+    Ok(Buffered::Complete(
+        chunks.concat().into(),
+        body.trailers().await?,
+    ))
+}
+
+// Rebuild a buffered body, along with its trailers
+fn replay(body: &Bytes, trailers: &Option<HeaderMap>) -> Body {
+    let trailers = match trailers {
+        Some(trailers) => trailers.clone(),
+        None => return Body::from(body.clone()),
+    };
+    let (mut sender, replayed) = Body::channel();
+    let body = body.clone();
+    tokio::spawn(async move {
+        if sender.send_data(body).await.is_ok() {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    replayed
+}
+
+// Hold a guard until the body has been sent, or dropped by the client
+fn hold<T: Send + 'static>(body: Body, guard: T) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    let (sender, held) = Body::channel();
+    tokio::spawn(async move {
+        pipe(body, sender).await;
+        drop(guard);
+    });
+    held
+}
+
+// Copy a body, along with its trailers, into the sender of another
+async fn pipe(mut body: Body, mut sender: Sender) {
+    while let Some(chunk) = body.data().await {
+        let sent = match chunk {
+            Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+            Err(_) => false,
+        };
+        if !sent {
+            return sender.abort();
+        }
+    }
+    match body.trailers().await {
+        Ok(Some(trailers)) => {
+            let _ = sender.send_trailers(trailers).await;
         }
+        Ok(None) => (),
+        Err(_) => sender.abort(),
     }
 }
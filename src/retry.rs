@@ -0,0 +1,216 @@
+use hyper::{Body, Method, Response};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::error::Error as ProximaError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryError {
+    Connect,
+    Timeout,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum RetryOn {
+    Status(u16),
+    Error(RetryError),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_on")]
+    pub on: Vec<RetryOn>,
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+    #[serde(default = "default_budget")]
+    pub budget: u32,
+    #[serde(default = "default_methods")]
+    pub methods: Vec<String>,
+    #[serde(default = "default_max_body")]
+    pub max_body: u64,
+    #[serde(skip, default = "full_budget")]
+    tokens: Arc<Mutex<f64>>,
+}
+
+// Total attempts, including the first request
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_on() -> Vec<RetryOn> {
+    vec![
+        RetryOn::Error(RetryError::Connect),
+        RetryOn::Error(RetryError::Timeout),
+        RetryOn::Status(502),
+        RetryOn::Status(503),
+    ]
+}
+
+// Base backoff of 100 milliseconds, doubled on each retry
+fn default_backoff() -> u64 {
+    100
+}
+
+fn default_max_backoff() -> u64 {
+    2000
+}
+
+// Retries may add at most 20 percent on top of regular requests
+fn default_budget() -> u32 {
+    20
+}
+
+// Only idempotent methods are retried by default
+fn default_methods() -> Vec<String> {
+    ["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+}
+
+// Buffer request bodies of up to 64KiB, so they can be replayed
+fn default_max_body() -> u64 {
+    65536
+}
+
+// Retry budget tokens are capped, so a quiet period can't save up a burst of retries
+const BUDGET_MAX_TOKENS: f64 = 10.0;
+
+fn full_budget() -> Arc<Mutex<f64>> {
+    Arc::new(Mutex::new(BUDGET_MAX_TOKENS))
+}
+
+impl Hash for RetryPolicy {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.attempts.hash(state);
+        self.on.hash(state);
+        self.backoff.hash(state);
+        self.max_backoff.hash(state);
+        self.budget.hash(state);
+        self.methods.hash(state);
+        self.max_body.hash(state);
+    }
+}
+
+impl RetryPolicy {
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+    }
+
+    // Each request adds to the budget, up to a cap. The budget starts full.
+    pub fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        *tokens = (*tokens + self.budget as f64 / 100.0).min(BUDGET_MAX_TOKENS);
+    }
+
+    pub fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn should_retry(&self, result: &Result<Response<Body>, ProximaError>) -> bool {
+        self.on.iter().any(|on| match (on, result) {
+            (RetryOn::Status(status), Ok(response)) => response.status().as_u16() == *status,
+            (RetryOn::Error(RetryError::Connect), Err(ProximaError::Connection)) => true,
+            (RetryOn::Error(RetryError::Timeout), Err(ProximaError::ConnectionTimeout)) => true,
+            _ => false,
+        })
+    }
+
+    // Exponential backoff with full jitter, so retrying clients don't stay in lockstep
+    pub fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .backoff
+            .saturating_mul(2u64.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(yaml: &str) -> RetryPolicy {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn budget_limits_retries() {
+        let policy = policy("budget: 20");
+        for _ in 0..10 {
+            assert!(policy.withdraw());
+        }
+        assert!(!policy.withdraw());
+
+        // Five requests at 20 percent earn one retry
+        for _ in 0..4 {
+            policy.deposit();
+        }
+        assert!(!policy.withdraw());
+        policy.deposit();
+        assert!(policy.withdraw());
+        assert!(!policy.withdraw());
+    }
+
+    #[test]
+    fn budget_is_capped() {
+        let policy = policy("budget: 100");
+        for _ in 0..100 {
+            policy.deposit();
+        }
+        for _ in 0..10 {
+            assert!(policy.withdraw());
+        }
+        assert!(!policy.withdraw());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let doubling = policy("{backoff: 100, max_backoff: 1000}");
+        for _ in 0..50 {
+            assert!(doubling.delay(1) <= Duration::from_millis(100));
+            assert!(doubling.delay(2) <= Duration::from_millis(200));
+            assert!(doubling.delay(3) <= Duration::from_millis(400));
+            assert!(doubling.delay(40) <= Duration::from_millis(1000));
+        }
+
+        let immediate = policy("{backoff: 0}");
+        assert_eq!(immediate.delay(3), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn retries_configured_outcomes() {
+        let policy = policy("on: [503, timeout]");
+        let status = |code: u16| -> Result<Response<Body>, ProximaError> {
+            Ok(Response::builder()
+                .status(code)
+                .body(Body::empty())
+                .unwrap())
+        };
+        assert!(policy.should_retry(&status(503)));
+        assert!(!policy.should_retry(&status(502)));
+        assert!(policy.should_retry(&Err(ProximaError::ConnectionTimeout)));
+        assert!(!policy.should_retry(&Err(ProximaError::Connection)));
+
+        assert!(policy.allows_method(&Method::GET));
+        assert!(!policy.allows_method(&Method::POST));
+    }
+}
@@ -0,0 +1,152 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+
+use crate::path::ProxyPath;
+
+// Rewrites apply to the remainder of the request path after the route key, before it is
+// appended to the upstream url
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Rewrite {
+    Strip(usize),
+    Prefix {
+        from: String,
+        to: String,
+    },
+    Regex {
+        pattern: RewriteRegex,
+        replace: String,
+    },
+    Path(String),
+}
+
+// Patterns are compiled as the config is loaded, so bad patterns are reported as config errors
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct RewriteRegex(Regex);
+
+impl TryFrom<String> for RewriteRegex {
+    type Error = regex::Error;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        Ok(RewriteRegex(Regex::new(&pattern)?))
+    }
+}
+
+impl From<RewriteRegex> for String {
+    fn from(regex: RewriteRegex) -> String {
+        regex.0.as_str().to_string()
+    }
+}
+
+impl Hash for RewriteRegex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+impl Rewrite {
+    pub fn apply(&self, path: &ProxyPath) -> String {
+        let suffix = path.suffix();
+        let rewritten = match self {
+            Rewrite::Strip(count) => suffix
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .skip(*count)
+                .collect::<Vec<&str>>()
+                .join("/"),
+            Rewrite::Prefix { from, to } => {
+                let from = from.trim_matches('/');
+                let to = to.trim_matches('/');
+                match suffix.strip_prefix(from) {
+                    Some("") => to.to_string(),
+                    Some(rest) if from.is_empty() => format!("{}/{}", to, rest),
+                    Some(rest) if rest.starts_with('/') => {
+                        format!("{}{}", to, rest)
+                    }
+                    _ => suffix.clone(),
+                }
+            }
+            Rewrite::Regex { pattern, replace } => {
+                pattern.0.replace(&suffix, replace.as_str()).into_owned()
+            }
+            // Fixed paths can include params captured from the route key
+            Rewrite::Path(fixed) => path.interpolate_path(fixed),
+        };
+        log::debug!("\"Rewrote path {} to {}\"", suffix, rewritten);
+        rewritten.trim_start_matches('/').to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(from: &str, to: &str) -> Rewrite {
+        Rewrite::Prefix {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    fn regex(pattern: &str, replace: &str) -> Rewrite {
+        Rewrite::Regex {
+            pattern: RewriteRegex::try_from(pattern.to_string()).unwrap(),
+            replace: replace.to_string(),
+        }
+    }
+
+    // Match the first segment as the route key, leaving the rest as the suffix
+    fn routed(path: &str) -> ProxyPath {
+        let mut path = ProxyPath::new(path);
+        assert!(path.capture("billing"));
+        path
+    }
+
+    #[test]
+    fn rewrites() {
+        let cases = [
+            (Rewrite::Strip(1), "/billing/v2/invoices", "invoices"),
+            (Rewrite::Strip(2), "/billing/v2/invoices", ""),
+            (Rewrite::Strip(1), "/billing", ""),
+            (prefix("v2", "api"), "/billing/v2/invoices", "api/invoices"),
+            (prefix("/v2/", "/api/"), "/billing/v2", "api"),
+            (prefix("v2", "api"), "/billing/v20/invoices", "v20/invoices"),
+            (prefix("v2", ""), "/billing/v2/invoices", "invoices"),
+            (prefix("", "api"), "/billing/invoices", "api/invoices"),
+            (prefix("", "api"), "/billing", "api"),
+            (prefix("v2", "api"), "/billing", ""),
+            (
+                regex(r"^v\d+/(.*)$", "api/$1"),
+                "/billing/v2/invoices",
+                "api/invoices",
+            ),
+            (regex("^x", "y"), "/billing/v2", "v2"),
+            (regex("^$", "index"), "/billing", "index"),
+            (
+                Rewrite::Path("/api/invoices".to_string()),
+                "/billing/v2/x",
+                "api/invoices",
+            ),
+            (Rewrite::Path("/".to_string()), "/billing/v2", ""),
+        ];
+        for (rewrite, path, expected) in cases {
+            assert_eq!(
+                rewrite.apply(&routed(path)),
+                expected,
+                "{:?} {}",
+                rewrite,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn path_encodes_params() {
+        let mut path = ProxyPath::new("/billing/a%20b/c d/x");
+        assert!(path.capture("billing/{id}/{name}"));
+        let rewrite = Rewrite::Path("/api/{id}/{name}".to_string());
+        assert_eq!(rewrite.apply(&path), "api/a%20b/c%20d");
+    }
+}
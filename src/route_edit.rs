@@ -0,0 +1,385 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+
+use crate::config::{ConfigMap, Route};
+use crate::config_include::ConfigFormat;
+use crate::error::Error as ProximaError;
+
+// Route changes are kept in memory, unless persist is set
+#[derive(Deserialize, Debug, Default)]
+pub struct RouteEditParams {
+    #[serde(default)]
+    pub persist: bool,
+}
+
+// A route change requested through the api. Patches are JSON merge patches (RFC 7396).
+#[derive(Debug, Clone)]
+pub enum RouteEdit {
+    Put(Value),
+    Patch(Value),
+    Delete,
+}
+
+// A change made through the api, kept so it can be reapplied on top of reloaded configs.
+// The route is also kept as it was sent, as credentials are left out when it is serialized.
+#[derive(Debug, Clone)]
+pub struct RouteChange {
+    pub keys: Vec<String>,
+    pub route: Option<Route>,
+    pub value: Option<Value>,
+}
+
+pub fn parse(value: &Value) -> Result<Route, ProximaError> {
+    serde_json::from_value(value.clone()).map_err(|e| ProximaError::InvalidRoute(e.to_string()))
+}
+
+// Split a route path into keys. Keys may contain slashes, so the longest existing key is
+// preferred at each level, falling back to a single segment for new routes.
+pub fn resolve_path(routes: &ConfigMap, path: &str) -> Vec<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut keys = Vec::new();
+    let mut current = Some(routes);
+    let mut index = 0;
+
+    while index < segments.len() {
+        let found = current.and_then(|map| {
+            (index + 1..=segments.len())
+                .rev()
+                .map(|end| (end, segments[index..end].join("/")))
+                .find(|(_, key)| map.contains_key(key))
+        });
+        let (end, key) = found.unwrap_or((index + 1, segments[index].to_string()));
+
+        current = match current.and_then(|map| map.get(&key)) {
+            Some(Route::ConfigMap(map)) => Some(map),
+            _ => None,
+        };
+        keys.push(key);
+        index = end;
+    }
+
+    keys
+}
+
+pub fn get<'a>(routes: &'a ConfigMap, keys: &[String]) -> Option<&'a Route> {
+    let (last, parents) = keys.split_last()?;
+    let mut current = routes;
+    for key in parents {
+        match current.get(key) {
+            Some(Route::ConfigMap(map)) => current = map,
+            _ => return None,
+        }
+    }
+    current.get(last)
+}
+
+// Insert or remove a route, creating any missing folders along the way
+pub fn set(
+    routes: &mut ConfigMap,
+    keys: &[String],
+    route: Option<Route>,
+) -> Result<(), ProximaError> {
+    let (last, parents) = match keys.split_last() {
+        Some(k) => k,
+        None => return Err(ProximaError::InvalidRoute("empty route path".to_string())),
+    };
+
+    let mut current = routes;
+    for (depth, key) in parents.iter().enumerate() {
+        if route.is_some() && !current.contains_key(key) {
+            current.insert(key.clone(), Route::ConfigMap(Box::default()));
+        }
+        current = match current.get_mut(key) {
+            Some(Route::ConfigMap(map)) => map,
+            Some(Route::Endpoint(_)) => {
+                return Err(ProximaError::InvalidRoute(format!(
+                    "{} is an endpoint, not a folder",
+                    keys[..=depth].join("/")
+                )))
+            }
+            None => return Err(ProximaError::UnknownProxy),
+        };
+    }
+
+    match route {
+        Some(route) => {
+            current.insert(last.clone(), route);
+        }
+        None => {
+            current.remove(last).ok_or(ProximaError::UnknownProxy)?;
+        }
+    }
+    Ok(())
+}
+
+// Apply a JSON merge patch (RFC 7396), where null removes a field
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(fields) => {
+            if !target.is_object() {
+                *target = Value::Object(serde_json::Map::new());
+            }
+            if let Value::Object(existing) = target {
+                for (key, value) in fields {
+                    if value.is_null() {
+                        existing.remove(&key);
+                    } else {
+                        merge_patch(existing.entry(key).or_insert(Value::Null), value);
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+// Write a route change back to the config file, returning the original contents so the
+// change can be undone. Patches are merged into the route as written in the file, so secret
+// references and credentials the patch doesn't touch are kept as they are.
+pub fn write_back(
+    location: &str,
+    keys: &[String],
+    edit: &RouteEdit,
+) -> Result<Vec<u8>, ProximaError> {
+    let path = Path::new(location);
+    if !path.is_file() {
+        return Err(ProximaError::InvalidRoute(
+            "persisting routes requires a single local config file".to_string(),
+        ));
+    }
+
+    let original = std::fs::read(path)?;
+    let format = ConfigFormat::from_extension(path).unwrap_or(ConfigFormat::Yaml);
+    let mut document: serde_yaml::Value = format.parse(&original)?;
+
+    let mut keys_with_root = vec!["routes".to_string()];
+    keys_with_root.extend_from_slice(keys);
+    let route = match edit {
+        RouteEdit::Put(value) => Some(serde_yaml::to_value(value)?),
+        RouteEdit::Patch(patch) => {
+            let existing = keys_with_root
+                .iter()
+                .try_fold(&document, |current, key| current.get(key.as_str()))
+                .ok_or(ProximaError::UnknownProxy)?;
+            let mut merged = serde_json::to_value(existing)?;
+            merge_patch(&mut merged, patch.clone());
+            Some(serde_yaml::to_value(merged)?)
+        }
+        RouteEdit::Delete => None,
+    };
+    set_value(&mut document, &keys_with_root, route)?;
+
+    std::fs::write(path, format.serialize(&document)?)?;
+    Ok(original)
+}
+
+// Set or remove the route at keys within a folder, as it was sent through the api
+pub fn set_within(
+    folder: &mut Value,
+    keys: &[String],
+    value: Option<Value>,
+) -> Result<(), ProximaError> {
+    let mut document = serde_yaml::to_value(&*folder)?;
+    let value = value.map(serde_yaml::to_value).transpose()?;
+    set_value(&mut document, keys, value)?;
+    *folder = serde_json::to_value(document)?;
+    Ok(())
+}
+
+fn set_value(
+    document: &mut serde_yaml::Value,
+    keys: &[String],
+    value: Option<serde_yaml::Value>,
+) -> Result<(), ProximaError> {
+    let (last, parents) = match keys.split_last() {
+        Some(k) => k,
+        None => return Err(ProximaError::InvalidRoute("empty route path".to_string())),
+    };
+
+    let mut current = document;
+    for key in parents {
+        let map = current
+            .as_mapping_mut()
+            .ok_or_else(|| ProximaError::InvalidRoute(format!("{} is not a folder", key)))?;
+        let key = serde_yaml::Value::String(key.clone());
+        if value.is_some() && !map.contains_key(&key) {
+            map.insert(key.clone(), serde_yaml::Value::Mapping(Default::default()));
+        }
+        current = map.get_mut(&key).ok_or(ProximaError::UnknownProxy)?;
+    }
+
+    let map = current
+        .as_mapping_mut()
+        .ok_or_else(|| ProximaError::InvalidRoute(format!("{} is not a folder", last)))?;
+    let key = serde_yaml::Value::String(last.clone());
+    match value {
+        Some(value) => {
+            map.insert(key, value);
+        }
+        None => {
+            map.remove(&key).ok_or(ProximaError::UnknownProxy)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ROUTES: &str = "
+api/v1:
+  users:
+    proxy:
+      url: http://users:8080
+static:
+  static:
+    body: ok
+";
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    // The examples from appendix A of RFC 7396
+    #[test]
+    fn merge_patch_examples() {
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (
+                json!({"a": "b"}),
+                json!({"b": "c"}),
+                json!({"a": "b", "b": "c"}),
+            ),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (
+                json!({"a": "b", "b": "c"}),
+                json!({"a": null}),
+                json!({"b": "c"}),
+            ),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (
+                json!({"a": [{"b": "c"}]}),
+                json!({"a": [1]}),
+                json!({"a": [1]}),
+            ),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (
+                json!({"e": null}),
+                json!({"a": 1}),
+                json!({"e": null, "a": 1}),
+            ),
+            (
+                json!([1, 2]),
+                json!({"a": "b", "c": null}),
+                json!({"a": "b"}),
+            ),
+            (
+                json!({}),
+                json!({"a": {"bb": {"ccc": null}}}),
+                json!({"a": {"bb": {}}}),
+            ),
+        ];
+        for (mut target, patch, expected) in cases {
+            merge_patch(&mut target, patch.clone());
+            assert_eq!(target, expected, "patch {}", patch);
+        }
+    }
+
+    #[test]
+    fn resolve_prefers_longest_key() {
+        let routes: ConfigMap = serde_yaml::from_str(ROUTES).unwrap();
+        assert_eq!(
+            resolve_path(&routes, "/api/v1/users"),
+            keys(&["api/v1", "users"])
+        );
+        assert_eq!(
+            resolve_path(&routes, "static/new"),
+            keys(&["static", "new"])
+        );
+        assert_eq!(resolve_path(&routes, "api/v2"), keys(&["api", "v2"]));
+    }
+
+    #[test]
+    fn set_and_get_routes() {
+        let mut routes: ConfigMap = serde_yaml::from_str(ROUTES).unwrap();
+        let route = parse(&json!({"static": {"body": "new"}})).unwrap();
+        set(&mut routes, &keys(&["new", "nested"]), Some(route)).unwrap();
+        assert!(get(&routes, &keys(&["new", "nested"])).is_some());
+
+        set(&mut routes, &keys(&["api/v1", "users"]), None).unwrap();
+        assert!(get(&routes, &keys(&["api/v1", "users"])).is_none());
+        assert!(matches!(
+            set(&mut routes, &keys(&["missing", "route"]), None),
+            Err(ProximaError::UnknownProxy)
+        ));
+        assert!(matches!(
+            set(&mut routes, &keys(&["static", "static", "x"]), None),
+            Err(ProximaError::InvalidRoute(_))
+        ));
+    }
+
+    #[test]
+    fn set_within_folder() {
+        let mut folder = json!({"users": {"proxy": {"url": "http://users:8080"}}});
+        set_within(
+            &mut folder,
+            &keys(&["v2", "users"]),
+            Some(json!({"static": {"body": "ok"}})),
+        )
+        .unwrap();
+        set_within(&mut folder, &keys(&["users"]), None).unwrap();
+        assert_eq!(folder, json!({"v2": {"users": {"static": {"body": "ok"}}}}));
+    }
+
+    #[test]
+    fn write_back_keeps_untouched_fields() {
+        let directory =
+            std::env::temp_dir().join(format!("proxima-write-back-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let location = directory.join("config.yaml");
+        let config = "
+routes:
+  users:
+    proxy:
+      url: http://users:8080
+      authentication:
+        basic:
+          username: users
+          password: ${USERS_PASSWORD}
+";
+        std::fs::write(&location, config).unwrap();
+        let location = location.display().to_string();
+
+        let patch = RouteEdit::Patch(json!({"proxy": {"url": "http://users-v2:8080"}}));
+        let original = write_back(&location, &keys(&["users"]), &patch).unwrap();
+        assert_eq!(original, config.as_bytes());
+
+        let written: serde_yaml::Value =
+            serde_yaml::from_str(&std::fs::read_to_string(&location).unwrap()).unwrap();
+        let proxy = &written["routes"]["users"]["proxy"];
+        assert_eq!(proxy["url"].as_str(), Some("http://users-v2:8080"));
+        assert_eq!(
+            proxy["authentication"]["basic"]["password"].as_str(),
+            Some("${USERS_PASSWORD}")
+        );
+
+        write_back(&location, &keys(&["users"]), &RouteEdit::Delete).unwrap();
+        assert!(matches!(
+            write_back(&location, &keys(&["users"]), &patch),
+            Err(ProximaError::UnknownProxy)
+        ));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+use serde_yaml::Value;
+use std::path::PathBuf;
+
+use crate::error::Error as ProximaError;
+
+// Fields which may reference secrets, across all auth types
+const SECRET_FIELDS: &[&str] = &["password", "token", "client_secret"];
+
+// Replace ${env:NAME} and ${file:/path} references within secret fields, and $${ with ${
+pub fn resolve(value: &mut Value) -> Result<(), ProximaError> {
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map.iter_mut() {
+                let is_secret = key
+                    .as_str()
+                    .map(|k| SECRET_FIELDS.contains(&k))
+                    .unwrap_or(false);
+
+                match value {
+                    Value::String(s) if is_secret => *s = interpolate(s)?,
+                    _ => resolve(value)?,
+                }
+            }
+        }
+        Value::Sequence(seq) => {
+            for value in seq.iter_mut() {
+                resolve(value)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// Files referenced by ${file:/path} within secret fields
+pub fn files(value: &Value) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    match value {
+        Value::Mapping(map) => {
+            for (key, value) in map {
+                let is_secret = key
+                    .as_str()
+                    .map(|k| SECRET_FIELDS.contains(&k))
+                    .unwrap_or(false);
+
+                match value {
+                    Value::String(s) if is_secret => {
+                        files.extend(parts(s).into_iter().filter_map(|part| match part {
+                            Part::Reference(reference) => {
+                                reference.strip_prefix("file:").map(PathBuf::from)
+                            }
+                            Part::Text(_) => None,
+                        }))
+                    }
+                    _ => files.extend(self::files(value)),
+                }
+            }
+        }
+        Value::Sequence(seq) => {
+            for value in seq {
+                files.extend(self::files(value));
+            }
+        }
+        _ => (),
+    }
+    files
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Reference(&'a str),
+}
+
+// Split a secret field into text and references. $${ is written as a literal ${, and a
+// ${ without a closing brace is left as written.
+fn parts(input: &str) -> Vec<Part<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = input;
+
+    while let Some(start) = rest.find('$') {
+        let after = &rest[start..];
+        if after.starts_with("$${") {
+            parts.push(Part::Text(&rest[..start]));
+            parts.push(Part::Text("${"));
+            rest = &rest[start + 3..];
+        } else if let (true, Some(end)) = (after.starts_with("${"), after.find('}')) {
+            parts.push(Part::Text(&rest[..start]));
+            parts.push(Part::Reference(&after[2..end]));
+            rest = &after[end + 1..];
+        } else {
+            parts.push(Part::Text(&rest[..start + 1]));
+            rest = &rest[start + 1..];
+        }
+    }
+
+    parts.push(Part::Text(rest));
+    parts
+}
+
+fn interpolate(input: &str) -> Result<String, ProximaError> {
+    let mut output = String::new();
+    for part in parts(input) {
+        match part {
+            Part::Text(text) => output.push_str(text),
+            Part::Reference(reference) => output.push_str(&lookup(reference)?),
+        }
+    }
+    Ok(output)
+}
+
+fn lookup(reference: &str) -> Result<String, ProximaError> {
+    match reference.split_once(':') {
+        Some(("env", name)) => std::env::var(name).map_err(|_| {
+            ProximaError::UnresolvedSecret(format!("environment variable {} is not set", name))
+        }),
+        Some(("file", path)) => match std::fs::read_to_string(path) {
+            // Mounted secrets commonly end with a newline
+            Ok(contents) => Ok(contents.trim_end_matches(&['\r', '\n'][..]).to_string()),
+            Err(e) => Err(ProximaError::UnresolvedSecret(format!(
+                "unable to read {}: {}",
+                path, e
+            ))),
+        },
+        _ => Err(ProximaError::UnresolvedSecret(format!(
+            "unknown secret reference ${{{}}}",
+            reference
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_references() {
+        std::env::set_var("PROXIMA_TEST_SECRET", "hunter2");
+        assert_eq!(
+            interpolate("a${env:PROXIMA_TEST_SECRET}b").unwrap(),
+            "ahunter2b"
+        );
+        assert!(interpolate("${env:PROXIMA_TEST_UNSET}").is_err());
+        assert!(interpolate("${vault:secret}").is_err());
+    }
+
+    #[test]
+    fn interpolate_escapes() {
+        assert_eq!(interpolate("p$${env:HOME}").unwrap(), "p${env:HOME}");
+        assert_eq!(interpolate("$$${").unwrap(), "$${");
+        assert_eq!(interpolate("pa$$word").unwrap(), "pa$$word");
+        assert_eq!(interpolate("${unclosed").unwrap(), "${unclosed");
+    }
+
+    #[test]
+    fn secret_files() {
+        let value: Value = serde_yaml::from_str(
+            "routes:\n  api:\n    proxy:\n      authentication:\n        basic:\n          username: ${file:/not/a/secret}\n          password: ${file:/run/secrets/a}$${file:/escaped}\n",
+        )
+        .unwrap();
+        assert_eq!(files(&value), vec![PathBuf::from("/run/secrets/a")]);
+    }
+}
@@ -1,8 +1,11 @@
 use async_trait::async_trait;
+use digest_auth::AuthorizationHeader;
+use hyper::header::AUTHORIZATION;
 use hyper::HeaderMap;
 use hyper::Method;
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::net::SocketAddr;
 
 use crate::auth::anonymous::AnonymousAuth;
@@ -61,15 +64,15 @@ pub trait EndpointSecurity {
         }
     }
 
+    // Returns the identity of the authenticated client, if the auth method provides one
     async fn auth(
         &self,
         headers: &HeaderMap,
         method: &Method,
         client: &SocketAddr,
-    ) -> Result<(), ProximaError> {
+    ) -> Result<Option<String>, ProximaError> {
         self.authorize_whitelist(method, client).await?;
-        self.authenticate_client(headers, method, client).await?;
-        Ok(())
+        self.authenticate_client(headers, method, client).await
     }
 
     async fn authorize_whitelist(
@@ -90,30 +93,32 @@ pub trait EndpointSecurity {
         headers: &HeaderMap,
         method: &Method,
         client_addr: &SocketAddr,
-    ) -> Result<(), ProximaError> {
+    ) -> Result<Option<String>, ProximaError> {
         // If endpoint is locked down, verify credentials
         let security = self.security();
         if let Some(security) = security {
             if let Some(clientlist) = &security.client {
                 log::debug!("Proxy is locked");
-                clientlist.authorize(headers, method, client_addr).await?
+                return clientlist.authorize(headers, method, client_addr).await;
             }
         }
-        Ok(())
+        Ok(None)
     }
 }
 
 impl AuthorizedClients {
+    // Basic and Digest clients are identified by their username, and JWKS clients by the
+    // token's sub claim
     pub async fn authorize(
         &self,
         headers: &HeaderMap,
         method: &Method,
         client_addr: &SocketAddr,
-    ) -> Result<(), ProximaError> {
+    ) -> Result<Option<String>, ProximaError> {
         // Test for Anonymous authorization
         if let Some(auth) = &self.anonymous {
             match auth.authorize(headers, method, client_addr).await {
-                Ok(_) => return Ok(()),
+                Ok(_) => return Ok(None),
                 Err(e) => {
                     log::debug!("Anonymous client was blocked: {e}");
                 }
@@ -130,7 +135,7 @@ impl AuthorizedClients {
                     _ => return Err(e),
                 }
             } else {
-                return Ok(());
+                return Ok(basic_username(headers));
             }
         }
 
@@ -145,7 +150,7 @@ impl AuthorizedClients {
                     _ => return Err(e),
                 }
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
 
@@ -165,7 +170,7 @@ impl AuthorizedClients {
                     }
                 }
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
 
@@ -179,7 +184,7 @@ impl AuthorizedClients {
                     _ => return Err(e),
                 }
             } else {
-                return Ok(());
+                return Ok(bearer_claim(headers, "sub"));
             }
         }
 
@@ -195,7 +200,7 @@ impl AuthorizedClients {
                     _ => return Err(ProximaError::UnauthorizedClientDigest),
                 }
             } else {
-                return Ok(());
+                return Ok(digest_username(headers));
             }
         }
 
@@ -209,6 +214,38 @@ impl AuthorizedClients {
     }
 }
 
+fn authorization<'a>(headers: &'a HeaderMap, scheme: &str) -> Option<&'a str> {
+    let (client_scheme, value) = headers.get(AUTHORIZATION)?.to_str().ok()?.split_once(' ')?;
+    match client_scheme.eq_ignore_ascii_case(scheme) {
+        true => Some(value.trim()),
+        false => None,
+    }
+}
+
+fn basic_username(headers: &HeaderMap) -> Option<String> {
+    let decoded = base64::decode(authorization(headers, "basic")?).ok()?;
+    let user_pass = String::from_utf8(decoded).ok()?;
+    user_pass.split_once(':').map(|(user, _)| user.to_string())
+}
+
+fn digest_username(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    AuthorizationHeader::parse(header).ok().map(|h| h.username)
+}
+
+// Read a claim from a bearer JWT. The token is only decoded here, as verifying it is left
+// to the endpoint's security.
+pub fn bearer_claim(headers: &HeaderMap, name: &str) -> Option<String> {
+    let payload = authorization(headers, "bearer")?.split('.').nth(1)?;
+    let bytes = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: Value = serde_json::from_slice(&bytes).ok()?;
+    match claims.get(name)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Null => None,
+        other => Some(other.to_string()),
+    }
+}
+
 pub fn display_security(item: &Option<Security>) -> bool {
     if let Some(security) = item {
         security.whitelist.is_none()
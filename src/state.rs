@@ -1,9 +1,6 @@
-use axum::{
-    extract::BodyStream,
-    http::{Response, StatusCode},
-};
+use axum::http::{Response, StatusCode};
 use clap::ArgMatches;
-use hyper::header::FORWARDED;
+use hyper::upgrade::OnUpgrade;
 use hyper::{Body, HeaderMap, Method};
 use serde_json::json;
 use serde_json::Value;
@@ -15,8 +12,10 @@ use crate::config;
 use crate::config::ConfigFile;
 use crate::config::{Config, Endpoint, Route};
 use crate::error::Error as ProximaError;
+use crate::forwarded;
 use crate::https::{ClientBuilder, HttpsClient};
 use crate::path::ProxyPath;
+use crate::reload;
 use crate::requests::ProxyRequest;
 use crate::security::EndpointSecurity;
 
@@ -26,6 +25,8 @@ type BoxResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
 pub struct State {
     pub config: Config,
     pub client: HttpsClient,
+    // Whether the listeners terminate TLS
+    pub tls: bool,
 }
 
 // Let's have this instead create client and vault_client, and add config at a later point
@@ -51,6 +52,7 @@ impl State {
         State {
             client,
             config: Config::default(),
+            tls: false,
         }
     }
 
@@ -99,9 +101,19 @@ impl State {
             self.client.clone(),
             vault_client,
         );
+        config.snapshot = opts.value_of("snapshot").map(|s| s.to_string());
 
-        // Get config from file or remote source
-        config.update().await?;
+        // Get config from file or remote source, falling back to the last known good snapshot
+        if let Err(e) = config.update().await {
+            log::error!("\"Unable to load config from {}: {}\"", &config_location, e);
+            if config.load_snapshot().await.is_err() {
+                return Err(e);
+            }
+            reload::retry(&config);
+        }
+
+        // Reload config on file changes and SIGHUP
+        reload::spawn(&config);
 
         // Update config
         self.config = config;
@@ -109,6 +121,26 @@ impl State {
         Ok(())
     }
 
+    // Api endpoints which change the config require the global api security, and are
+    // forbidden when it doesn't restrict clients
+    pub async fn authorize_admin(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        client_addr: &SocketAddr,
+    ) -> Result<(), ProximaError> {
+        match self.config.api_security().await {
+            Some(security) if security.client.is_some() || security.whitelist.is_some() => {
+                security.auth(headers, method, client_addr).await?;
+                Ok(())
+            }
+            _ => {
+                log::debug!("\"No api security configured, refusing config change\"");
+                Err(ProximaError::Forbidden)
+            }
+        }
+    }
+
     pub async fn config(&mut self) -> ConfigFile {
         let _ = self.config.renew().await;
         self.config.config_file().await
@@ -134,17 +166,21 @@ impl State {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn response(
         &mut self,
         method: Method,
         path: ProxyPath,
         query: Option<String>,
         request_headers: HeaderMap,
-        payload: Option<BodyStream>,
+        payload: Option<Body>,
         client_addr: SocketAddr,
+        host: Option<String>,
+        upgrade: Option<OnUpgrade>,
+        trace_id: String,
     ) -> Result<Response<Body>, ProximaError> {
         // Check if path exists in config
-        match self.config.get(path.clone()).await {
+        match self.config.get(path.clone(), host.as_deref()).await {
             // Looks like we found a match
             Ok((route, remainder)) => match route {
                 // Return these variants without checking for security
@@ -155,31 +191,25 @@ impl State {
                     ))
                     .unwrap()),
                 Route::Endpoint(entry) => {
-                    // Detect client IP
-                    let client = if let Some(x_forwarded) = &request_headers.get("x-forwarded-for")
-                    {
-                        match x_forwarded.to_str() {
-                            Ok(s) => s.parse().unwrap_or(client_addr),
-                            Err(e) => {
-                                log::error!("Unable to parse x-forwarded-for header: {}", e);
-                                client_addr
-                            }
-                        }
-                    } else if let Some(forwarded) = &request_headers.get(FORWARDED) {
-                        match forwarded.to_str() {
-                            Ok(s) => s.parse().unwrap_or(client_addr),
-                            Err(e) => {
-                                log::error!("Unable to parse forwarded header: {}", e);
-                                client_addr
-                            }
-                        }
-                    } else {
-                        client_addr
-                    };
+                    // Detect client IP, through any trusted proxies. Unresolved clients are logged
+                    // and forwarded as the peer, but authorized as an address no whitelist matches.
+                    let resolved = self.config.client_addr(&request_headers, client_addr).await;
+                    let client = resolved.unwrap_or(client_addr);
+                    let auth_client =
+                        resolved.unwrap_or_else(|| forwarded::unresolved(client_addr));
 
                     // Debug client addr
                     log::debug!("Client socket determined to be {}", &client);
 
+                    // Resolve conditional endpoints to the proxy matching this request
+                    let entry =
+                        match entry {
+                            Endpoint::Conditional(conditional) => Endpoint::Proxy(
+                                conditional.select(&method, &request_headers, query.as_deref())?,
+                            ),
+                            entry => entry,
+                        };
+
                     match entry {
                         Endpoint::HttpConfig(map) => Ok(Response::builder()
                             .status(StatusCode::OK)
@@ -204,40 +234,45 @@ impl State {
                             // then let them through. If they fail the global auth, then move on to endpoint auth.
                             // If endpoint auth does not exist, fail.
 
-                            if let Some(global_client) =
-                                self.config.config_file().await.global.security.auth
+                            let identity = if let Some(global_client) =
+                                self.config.auth(host.as_deref()).await
                             {
                                 log::debug!("Found global auth");
                                 match global_client
-                                    .auth(&request_headers, &method, &client_addr)
+                                    .auth(&request_headers, &method, &auth_client)
                                     .await
                                 {
-                                    Ok(_) => log::debug!("User passed global auth creds"),
+                                    Ok(identity) => {
+                                        log::debug!("User passed global auth creds");
+                                        identity
+                                    }
                                     Err(_) => {
                                         if endpoint.security().is_some() {
                                             log::debug!("Checking endpoint client auth");
                                             endpoint
-                                                .auth(&request_headers, &method, &client)
-                                                .await?;
+                                                .auth(&request_headers, &method, &auth_client)
+                                                .await?
                                         } else {
                                             return Err(ProximaError::Unauthorized);
                                         }
                                     }
                                 }
-                            } else {
+                            } else if endpoint.security().is_some() {
                                 // Check if there is endpoint security
-                                if endpoint.security().is_some() {
-                                    log::debug!("Checking endpoint client auth");
-                                    // Authorize client, and check for client whitelist
-                                    endpoint.auth(&request_headers, &method, &client).await?;
-                                }
-                            }
+                                log::debug!("Checking endpoint client auth");
+                                // Authorize client, and check for client whitelist
+                                endpoint
+                                    .auth(&request_headers, &method, &auth_client)
+                                    .await?
+                            } else {
+                                None
+                            };
 
-                            // Wrap Body if there is one
+                            // Pass the body through as it is, so trailers reach the upstream
                             let body = match payload {
                                 Some(p) => {
                                     log::debug!("Received body: {:#?}", &p);
-                                    Body::wrap_stream(p)
+                                    p
                                 }
                                 None => {
                                     log::debug!("Did not receive a body");
@@ -247,12 +282,22 @@ impl State {
 
                             let request = ProxyRequest {
                                 client: self.client.clone(),
+                                health: self.config.health.clone(),
+                                breakers: self.config.breakers.clone(),
                                 endpoint,
                                 method,
-                                path,
+                                path: remainder,
                                 body,
                                 request_headers,
                                 query,
+                                client_addr: client,
+                                peer_addr: client_addr,
+                                trusted_peer: self.config.trusts(client_addr.ip()).await,
+                                host,
+                                proto: if self.tls { "https" } else { "http" },
+                                upgrade,
+                                trace_id,
+                                identity,
                             };
                             request.go().await
                         }
@@ -262,27 +307,31 @@ impl State {
                             // Check if there is endpoint security
                             if endpoint.security().is_some() {
                                 // Authorize client, and check for client whitelist
-                                endpoint.auth(&request_headers, &method, &client).await?;
+                                endpoint
+                                    .auth(&request_headers, &method, &auth_client)
+                                    .await?;
                             } else if let Some(global_client) =
-                                self.config.config_file().await.global.security.auth
+                                self.config.auth(host.as_deref()).await
                             {
                                 global_client
-                                    .auth(&request_headers, &method, &client_addr)
-                                    .await?
+                                    .auth(&request_headers, &method, &auth_client)
+                                    .await?;
                             }
 
                             let mut response = Response::builder()
                                 .status(StatusCode::OK)
-                                .body(Body::from(endpoint.body))
+                                .body(Body::from(remainder.interpolate(&endpoint.body)))
                                 .unwrap();
 
                             if let Some(headers) = endpoint.headers {
                                 let headermap = response.headers_mut();
-                                headers.insert_headers(headermap)?;
+                                headers.insert_headers(headermap, &remainder)?;
                             };
 
                             Ok(response)
                         }
+                        // Conditional endpoints have already been resolved to a proxy above
+                        Endpoint::Conditional(_) => Err(ProximaError::UnknownProxy),
                         Endpoint::Redirect(endpoint) => {
                             log::debug!("Found redirect entry");
 
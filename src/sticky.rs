@@ -0,0 +1,191 @@
+use hyper::header::HeaderValue;
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use url::Url;
+
+use crate::conditional::Conditions;
+use crate::security;
+
+// Request attribute used as the consistent hashing key
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    Cookie(String),
+    Header(String),
+    ClientIp,
+    Claim(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Affinity {
+    #[serde(default = "default_cookie")]
+    pub cookie: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+fn default_cookie() -> String {
+    "proxima_affinity".to_string()
+}
+
+impl HashKey {
+    pub fn key(&self, headers: &HeaderMap, client_addr: &SocketAddr) -> Option<String> {
+        match self {
+            HashKey::Cookie(name) => Conditions::cookies(headers)
+                .into_iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v),
+            HashKey::Header(name) => headers
+                .get(name.as_str())
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string()),
+            HashKey::ClientIp => Some(client_addr.ip().to_string()),
+            HashKey::Claim(name) => security::bearer_claim(headers, name),
+        }
+    }
+}
+
+impl Affinity {
+    // The cookie names a member by a hash of its url, so backend addresses aren't exposed
+    pub fn member_id(member: &Url) -> String {
+        format!("{:016x}", stable_hash(member.as_str(), ""))
+    }
+
+    pub fn requested(&self, headers: &HeaderMap) -> Option<String> {
+        Conditions::cookies(headers)
+            .into_iter()
+            .find(|(k, _)| k == &self.cookie)
+            .map(|(_, v)| v)
+    }
+
+    // A cookie is only set when the client isn't already pinned to the member. Cookies
+    // set over TLS are marked secure, so they aren't sent over plain connections.
+    pub fn set_cookie(
+        &self,
+        headers: &HeaderMap,
+        member: &Url,
+        secure: bool,
+    ) -> Option<HeaderValue> {
+        let id = Affinity::member_id(member);
+        if self.requested(headers).as_deref() == Some(id.as_str()) {
+            return None;
+        }
+        let mut cookie = match self.max_age {
+            Some(max_age) => format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly",
+                self.cookie, id, max_age
+            ),
+            None => format!("{}={}; Path=/; HttpOnly", self.cookie, id),
+        };
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        HeaderValue::from_str(&cookie).ok()
+    }
+}
+
+// FNV-1a followed by a 64 bit finalizer. This needs to match across restarts and
+// between proxima instances, which std's hashers don't promise.
+pub fn stable_hash(key: &str, member: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.bytes().chain([0u8]).chain(member.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// Weighted rendezvous hashing. Each member scores every key independently, so adding or
+// removing a member only moves the keys which that member wins or loses.
+pub fn score(key: &str, member: &Url, weight: u32) -> f64 {
+    let hash = stable_hash(key, member.as_str());
+    let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    weight as f64 / -unit.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::COOKIE;
+
+    fn affinity(max_age: Option<u64>) -> Affinity {
+        Affinity {
+            cookie: default_cookie(),
+            max_age,
+        }
+    }
+
+    // Send back the name and value of a set cookie, as a client would
+    fn returned(set_cookie: &HeaderValue) -> HeaderMap {
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, format!("other=1; {}", cookie).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn cookie_round_trip() {
+        let affinity = affinity(Some(3600));
+        let member = Url::parse("http://api-1.api.svc:8080/v1").unwrap();
+        let set_cookie = affinity
+            .set_cookie(&HeaderMap::new(), &member, true)
+            .unwrap();
+        let id = Affinity::member_id(&member);
+        assert_eq!(
+            set_cookie.to_str().unwrap(),
+            format!(
+                "proxima_affinity={}; Path=/; Max-Age=3600; HttpOnly; Secure",
+                id
+            )
+        );
+        assert!(!set_cookie.to_str().unwrap().contains("api-1"));
+
+        let headers = returned(&set_cookie);
+        assert_eq!(affinity.requested(&headers), Some(id));
+        // Pinned clients aren't sent the cookie again, unless they move member
+        assert!(affinity.set_cookie(&headers, &member, true).is_none());
+        let other = Url::parse("http://api-2.api.svc:8080/v1").unwrap();
+        assert!(affinity.set_cookie(&headers, &other, true).is_some());
+    }
+
+    #[test]
+    fn session_cookie_over_plain_http() {
+        let member = Url::parse("http://api-1.api.svc:8080").unwrap();
+        let set_cookie = affinity(None)
+            .set_cookie(&HeaderMap::new(), &member, false)
+            .unwrap();
+        assert_eq!(
+            set_cookie.to_str().unwrap(),
+            format!(
+                "proxima_affinity={}; Path=/; HttpOnly",
+                Affinity::member_id(&member)
+            )
+        );
+    }
+
+    #[test]
+    fn hash_keys() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, "session=abc; theme=dark".parse().unwrap());
+        headers.insert("x-user", "42".parse().unwrap());
+        let client = "192.0.2.1:4000".parse().unwrap();
+        let key = |key: HashKey| key.key(&headers, &client);
+        assert_eq!(
+            key(HashKey::Cookie("session".to_string())).as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            key(HashKey::Header("x-user".to_string())).as_deref(),
+            Some("42")
+        );
+        assert_eq!(key(HashKey::ClientIp).as_deref(), Some("192.0.2.1"));
+        assert_eq!(key(HashKey::Cookie("missing".to_string())), None);
+    }
+}
@@ -0,0 +1,281 @@
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use tokio_rustls::TlsAcceptor;
+
+use crate::watch::FileWatcher;
+
+type BoxResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerTls {
+    pub certificates: Vec<ServerCertificate>,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ciphers: Vec<String>,
+}
+
+// Certificates are picked by matching the SNI server name against their hosts, falling
+// back to the first certificate
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ServerCertificate {
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Hash, PartialEq, Eq)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "tls1.2")]
+    Tls12,
+    #[serde(rename = "tls1.3")]
+    Tls13,
+}
+
+impl fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsVersion::Tls12 => f.write_str("tls1.2"),
+            TlsVersion::Tls13 => f.write_str("tls1.3"),
+        }
+    }
+}
+
+impl ServerTls {
+    pub fn server_config(&self) -> BoxResult<ServerConfig> {
+        let versions: &[&SupportedProtocolVersion] = match self.min_version {
+            TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            TlsVersion::Tls13 => &[&rustls::version::TLS13],
+        };
+
+        let mut resolver = CertificateResolver::default();
+        for certificate in &self.certificates {
+            let key = certificate.load()?;
+            let hosts = certificate.hosts.iter().map(|h| h.to_lowercase()).collect();
+            resolver.certificates.push((hosts, Arc::new(key)));
+        }
+        if resolver.certificates.is_empty() {
+            return Err("no certificates configured".into());
+        }
+
+        let suites = self.cipher_suites()?;
+        let usable = suites
+            .iter()
+            .any(|s| versions.iter().any(|v| v.version == s.version().version));
+        if !usable {
+            return Err(
+                format!("no ciphers can be used with {} or later", self.min_version).into(),
+            );
+        }
+
+        let mut config = ServerConfig::builder()
+            .with_cipher_suites(&suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    // Ciphers are named as in the IANA registry, such as TLS13_AES_256_GCM_SHA384 or
+    // TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+    fn cipher_suites(&self) -> BoxResult<Vec<SupportedCipherSuite>> {
+        if self.ciphers.is_empty() {
+            return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+        }
+        self.ciphers
+            .iter()
+            .map(|name| {
+                rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| format!("unsupported cipher {}", name).into())
+            })
+            .collect()
+    }
+
+    fn files(&self) -> Vec<PathBuf> {
+        self.certificates
+            .iter()
+            .flat_map(|c| [PathBuf::from(&c.cert), PathBuf::from(&c.key)])
+            .collect()
+    }
+}
+
+impl ServerCertificate {
+    fn load(&self) -> BoxResult<CertifiedKey> {
+        let chain = rustls_pemfile::certs(&mut reader(&self.cert)?)?;
+        if chain.is_empty() {
+            return Err(format!("no certificates found in {}", self.cert).into());
+        }
+
+        let key = rustls_pemfile::read_all(&mut reader(&self.key)?)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| format!("no private key found in {}", self.key))?;
+        let key = sign::any_supported_type(&rustls::PrivateKey(key))
+            .map_err(|_| format!("unsupported private key in {}", self.key))?;
+
+        Ok(CertifiedKey::new(
+            chain.into_iter().map(rustls::Certificate).collect(),
+            key,
+        ))
+    }
+}
+
+fn reader(path: &str) -> BoxResult<BufReader<File>> {
+    match File::open(path) {
+        Ok(file) => Ok(BufReader::new(file)),
+        Err(e) => Err(format!("unable to read {}: {}", path, e).into()),
+    }
+}
+
+#[derive(Default)]
+struct CertificateResolver {
+    certificates: Vec<(Vec<String>, Arc<CertifiedKey>)>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let matched = client_hello.server_name().and_then(|name| {
+            let name = name.to_lowercase();
+            self.certificates
+                .iter()
+                .find(|(hosts, _)| hosts.iter().any(|host| matches_host(host, &name)))
+        });
+        matched
+            .or_else(|| self.certificates.first())
+            .map(|(_, key)| key.clone())
+    }
+}
+
+// Wildcard hosts, such as *.example.com, match a single label
+fn matches_host(host: &str, name: &str) -> bool {
+    match host.strip_prefix("*.") {
+        Some(domain) => name
+            .split_once('.')
+            .map(|(label, rest)| !label.is_empty() && rest == domain)
+            .unwrap_or(false),
+        None => host == name,
+    }
+}
+
+// The TLS settings in use by the listeners, reloaded when the config or certificate files change
+#[derive(Clone, Default)]
+pub struct TlsTermination {
+    settings: Arc<RwLock<Option<ServerTls>>>,
+    acceptor: Arc<RwLock<Option<TlsAcceptor>>>,
+    watcher: Arc<Mutex<Option<FileWatcher>>>,
+    // Whether the listeners terminate TLS, once they have started
+    listening: Arc<RwLock<Option<bool>>>,
+}
+
+impl fmt::Debug for TlsTermination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsTermination")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl TlsTermination {
+    pub fn is_configured(&self) -> bool {
+        self.settings.read().unwrap().is_some()
+    }
+
+    pub fn acceptor(&self) -> Option<TlsAcceptor> {
+        self.acceptor.read().unwrap().clone()
+    }
+
+    pub fn listening(&self, tls: bool) {
+        *self.listening.write().unwrap() = Some(tls);
+    }
+
+    // Whether the listeners use TLS is decided as proxima starts, so TLS settings which are
+    // removed from the config are kept until a restart, and added ones wait for a restart
+    pub fn sync(&self, settings: &Option<ServerTls>) {
+        if *self.settings.read().unwrap() == *settings {
+            return;
+        }
+        if settings.is_none() && self.acceptor().is_some() {
+            log::warn!("\"TLS settings were removed, keeping current certificates until restart\"");
+            return;
+        }
+        if settings.is_some() && *self.listening.read().unwrap() == Some(false) {
+            log::warn!(
+                "\"TLS settings were added, restart proxima to terminate TLS on its listeners\""
+            );
+            return;
+        }
+        *self.settings.write().unwrap() = settings.clone();
+        self.reload();
+        self.watch();
+    }
+
+    // Certificates which fail to load leave the current certificates in place
+    fn reload(&self) {
+        let settings = match self.settings.read().unwrap().clone() {
+            Some(settings) => settings,
+            None => return,
+        };
+        match settings.server_config() {
+            Ok(config) => {
+                *self.acceptor.write().unwrap() = Some(TlsAcceptor::from(Arc::new(config)));
+                log::info!(
+                    "\"Loaded {} TLS certificate(s)\"",
+                    settings.certificates.len()
+                );
+            }
+            Err(e) => {
+                log::error!("\"Unable to load TLS certificates: {}\"", e);
+                metrics::increment_counter!("proxima_tls_reload_failures_total");
+            }
+        }
+    }
+
+    fn watch(&self) {
+        let files = match &*self.settings.read().unwrap() {
+            Some(settings) => settings.files(),
+            None => return,
+        };
+
+        let (mut watcher, mut changes) = match FileWatcher::new("certificate") {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                log::error!("\"Unable to watch TLS certificates: {}\"", e);
+                return;
+            }
+        };
+        watcher.watch(files);
+
+        // Replacing the watcher ends the changes of the previous one, and so its task
+        *self.watcher.lock().unwrap() = Some(watcher);
+
+        let me = self.clone();
+        tokio::spawn(async move {
+            while changes.next().await.is_some() {
+                log::info!("\"Reloading TLS certificates, triggered by file change\"");
+                me.reload();
+            }
+        });
+    }
+}
@@ -0,0 +1,108 @@
+use hyper::header::{CONNECTION, UPGRADE};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper::{Body, HeaderMap, Response};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::balance::Outstanding;
+
+// Buffer size for each direction of an upgraded connection
+const SPLICE_BUFFER: usize = 8192;
+
+// Requests asking to switch protocols, such as websockets, carry an Upgrade header
+// and list upgrade as a Connection option
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    let connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .any(|option| option.trim().eq_ignore_ascii_case("upgrade"));
+    connection_upgrade && headers.contains_key(UPGRADE)
+}
+
+// Once the 101 response has been passed back to the client, both connections are upgraded
+// and spliced together until either side closes, or no data has been sent for the idle timeout.
+// Load balanced members count the connection as outstanding until then.
+pub fn tunnel(
+    client: OnUpgrade,
+    response: &mut Response<Body>,
+    idle_timeout: Duration,
+    path: String,
+    outstanding: Option<Outstanding>,
+) {
+    let upstream = hyper::upgrade::on(response);
+    tokio::spawn(async move {
+        let _outstanding = outstanding;
+        let (client, upstream) = match tokio::try_join!(client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                log::error!(
+                    "{{\"error\": \"Unable to upgrade connection for {}: {}\"}}",
+                    path,
+                    e
+                );
+                return;
+            }
+        };
+
+        log::debug!("\"Upgraded connection opened for {}\"", path);
+        metrics::increment_gauge!("proxima_upgraded_connections", 1.0);
+        match splice(client, upstream, idle_timeout).await {
+            Ok((sent, received)) => log::debug!(
+                "\"Upgraded connection closed for {}, sent {} bytes, received {} bytes\"",
+                path,
+                sent,
+                received
+            ),
+            Err(e) => log::debug!("\"Upgraded connection for {} failed: {}\"", path, e),
+        }
+        metrics::decrement_gauge!("proxima_upgraded_connections", 1.0);
+    });
+}
+
+// Copy data in both directions, passing on half closes. Returns the bytes sent to, and
+// received from, the upstream.
+async fn splice(
+    client: Upgraded,
+    upstream: Upgraded,
+    idle_timeout: Duration,
+) -> std::io::Result<(u64, u64)> {
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let mut client_buffer = vec![0u8; SPLICE_BUFFER];
+    let mut upstream_buffer = vec![0u8; SPLICE_BUFFER];
+    let (mut sent, mut received) = (0u64, 0u64);
+    let (mut client_open, mut upstream_open) = (true, true);
+
+    while client_open || upstream_open {
+        tokio::select! {
+            read = client_read.read(&mut client_buffer), if client_open => match read? {
+                0 => {
+                    client_open = false;
+                    upstream_write.shutdown().await?;
+                }
+                n => {
+                    upstream_write.write_all(&client_buffer[..n]).await?;
+                    sent += n as u64;
+                }
+            },
+            read = upstream_read.read(&mut upstream_buffer), if upstream_open => match read? {
+                0 => {
+                    upstream_open = false;
+                    client_write.shutdown().await?;
+                }
+                n => {
+                    client_write.write_all(&upstream_buffer[..n]).await?;
+                    received += n as u64;
+                }
+            },
+            _ = tokio::time::sleep(idle_timeout) => {
+                log::debug!("\"Closing upgraded connection after {}ms idle\"", idle_timeout.as_millis());
+                break;
+            }
+        }
+    }
+
+    Ok((sent, received))
+}
@@ -5,11 +5,15 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use url::Url;
 
+use crate::balance::LoadBalance;
+use crate::health::HealthCheck;
+
 #[derive(Serialize, Deserialize, Debug, Clone, Hash)]
 #[serde(untagged)]
 pub enum Urls {
     Url(Url),
     UrlFailover(UrlFailover),
+    LoadBalance(LoadBalance),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -18,11 +22,15 @@ pub struct UrlFailover {
     #[serde(skip_serializing)]
     next: Arc<Mutex<usize>>,
     failover: Vec<Url>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
 }
 
 impl Hash for UrlFailover {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.failover.hash(state);
+        self.health_check.hash(state);
     }
 }
 
@@ -33,6 +41,7 @@ impl From<Url> for UrlFailover {
         UrlFailover {
             next: Arc::new(Mutex::new(0)),
             failover: vec,
+            health_check: None,
         }
     }
 }
@@ -62,6 +71,35 @@ impl<'a> UrlFailover {
         url
     }
 
+    // Skip past unhealthy members, staying on the current member if none are healthy
+    pub fn healthy<F: Fn(&Url) -> bool>(&'a self, is_healthy: F) -> &'a Url {
+        let current = self.current();
+        if is_healthy(current) {
+            return current;
+        }
+
+        for _ in 1..self.failover.len() {
+            let url = self.next();
+            if is_healthy(url) {
+                log::info!(
+                    "UrlFailover skipped unhealthy member {}, now using {}",
+                    current,
+                    url
+                );
+                return url;
+            }
+        }
+        self.current()
+    }
+
+    pub fn urls(&self) -> Vec<&Url> {
+        self.failover.iter().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failover.is_empty()
+    }
+
     pub fn path(&self) -> &str {
         log::trace!("UrlFailover getting path");
         self.current().path()
@@ -69,10 +107,19 @@ impl<'a> UrlFailover {
 }
 
 impl Urls {
+    pub fn members(&self) -> Vec<&Url> {
+        match self {
+            Urls::Url(url) => vec![url],
+            Urls::UrlFailover(urlfailover) => urlfailover.urls(),
+            Urls::LoadBalance(balance) => balance.members.iter().map(|m| m.url()).collect(),
+        }
+    }
+
     pub async fn path(&self) -> &str {
         match self {
             Urls::Url(url) => url.path(),
             Urls::UrlFailover(urlfailover) => urlfailover.path(),
+            Urls::LoadBalance(balance) => balance.path(),
         }
     }
 }
@@ -90,6 +137,7 @@ impl fmt::Display for Urls {
         match self {
             Urls::Url(url) => write!(f, "{}", url),
             Urls::UrlFailover(urlfailover) => write!(f, "{}", urlfailover),
+            Urls::LoadBalance(balance) => write!(f, "{}", balance),
         }
     }
 }
@@ -0,0 +1,290 @@
+use async_recursion::async_recursion;
+use hyper::header::HeaderName;
+use hyper::HeaderMap;
+use native_tls::Certificate;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::balance::Strategy;
+use crate::breaker::CircuitBreaker;
+use crate::config::{Config, ConfigMap, Endpoint, Proxy, Route};
+use crate::health::HealthCheck;
+use crate::https::HttpsClient;
+use crate::path::ProxyPath;
+use crate::security::Security;
+use crate::urls::Urls;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+    // Likely mistakes which don't stop the config from loading
+    pub warnings: Vec<Issue>,
+    // Circuit breaker settings of each member, and the route they were first seen in
+    breakers: HashMap<String, (String, CircuitBreaker)>,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub route: String,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.route, self.message)
+    }
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, route: &str, message: impl ToString) {
+        self.issues.push(Issue {
+            route: route.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn warn(&mut self, route: &str, message: impl ToString) {
+        self.warnings.push(Issue {
+            route: route.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    // Parse the config at location, then run semantic checks over every route
+    pub async fn build(location: &str) -> Report {
+        let mut report = Report::default();
+        let config = Config::new(location, None, HttpsClient::default(), None);
+
+        let (config_file, _) = match config.parse(None, None).await {
+            Ok(c) => c,
+            Err(e) => {
+                report.push("config", e);
+                return report;
+            }
+        };
+
+        if let Some(path) = &config_file.global.security.tls.import_cert {
+            match std::fs::read(path) {
+                Ok(cert) => {
+                    if let Err(e) = Certificate::from_pem(&cert) {
+                        report.push("global.security.tls.import_cert", e);
+                    }
+                }
+                Err(e) => report.push(
+                    "global.security.tls.import_cert",
+                    format!("unable to read {}: {}", path, e),
+                ),
+            }
+        }
+
+        if let Some(tls) = &config_file.global.server.tls {
+            if let Err(e) = tls.server_config() {
+                report.push("global.server.tls", e);
+            }
+        }
+
+        report.security("global.security.auth", &config_file.global.security.auth);
+        report.security("global.security.api", &config_file.global.security.api);
+        if let Some(api) = &config_file.global.security.api {
+            if api.client.is_none() && api.whitelist.is_none() {
+                report.warn(
+                    "global.security.api",
+                    "no clients or whitelist are set, so config changes are refused",
+                );
+            }
+        }
+        report.routes("", &config_file.routes).await;
+
+        for (host, vhost) in &config_file.hosts {
+            let prefix = format!("hosts.{}", host);
+            report.security(&format!("{}.auth", prefix), &vhost.auth);
+            report.routes(&prefix, &vhost.routes).await;
+        }
+
+        report
+    }
+
+    // Check a single route, such as one submitted through the api
+    pub async fn route(prefix: &str, key: &str, route: &Route) -> Report {
+        let mut report = Report::default();
+        let mut routes = ConfigMap::new();
+        routes.insert(key.to_string(), route.clone());
+        report.routes(prefix, &routes).await;
+        report
+    }
+
+    #[async_recursion]
+    async fn routes(&mut self, prefix: &str, routes: &ConfigMap) {
+        for (key, route) in routes {
+            let name = match prefix {
+                "" => key.clone(),
+                _ => format!("{}/{}", prefix, key),
+            };
+
+            if let Some(pattern) = ProxyPath::regex_key(key) {
+                if let Err(e) = ProxyPath::route_regex(pattern) {
+                    self.push(&name, e);
+                }
+            }
+
+            match route {
+                Route::ConfigMap(map) => self.routes(&name, map).await,
+                Route::Endpoint(endpoint) => self.endpoint(&name, endpoint).await,
+            }
+        }
+    }
+
+    async fn endpoint(&mut self, name: &str, endpoint: &Endpoint) {
+        match endpoint {
+            Endpoint::Proxy(proxy) => self.proxy(name, proxy),
+            Endpoint::Conditional(conditional) => {
+                for (index, rule) in conditional.rules.iter().enumerate() {
+                    let rule_name = format!("{} (rule {})", name, index);
+                    if let Some(headers) = &rule.when.headers {
+                        for header in headers.keys() {
+                            if let Err(e) = HeaderName::from_bytes(header.as_bytes()) {
+                                self.push(&rule_name, format!("header {}: {}", header, e));
+                            }
+                        }
+                    }
+                    self.proxy(&rule_name, &rule.proxy);
+                }
+                if let Some(proxy) = &conditional.default {
+                    self.proxy(&format!("{} (default)", name), proxy);
+                }
+                self.security(name, &conditional.security);
+
+                let secured: Vec<bool> = conditional
+                    .proxies()
+                    .map(|proxy| proxy.security.is_some())
+                    .collect();
+                if secured.contains(&true) && secured.contains(&false) {
+                    self.warn(
+                        name,
+                        "only some rules have security, and clients choose the rule through the request they send",
+                    );
+                }
+            }
+            Endpoint::Static(endpoint) => {
+                if let Some(headers) = &endpoint.headers {
+                    let mut map = HeaderMap::new();
+                    if let Err(e) = headers.insert_headers(&mut map, &ProxyPath::default()) {
+                        self.push(name, e);
+                    }
+                }
+                self.security(name, &endpoint.security);
+            }
+            Endpoint::Vault(vault) => {
+                if vault.template.is_some() {
+                    if let Err(e) = vault.handlebars().await {
+                        self.push(name, e);
+                    }
+                }
+            }
+            Endpoint::HttpConfig(_) | Endpoint::Redirect(_) => (),
+        }
+    }
+
+    fn proxy(&mut self, name: &str, proxy: &Proxy) {
+        match &proxy.url {
+            Urls::UrlFailover(failover) => {
+                if failover.is_empty() {
+                    self.push(name, "failover url list is empty");
+                }
+                self.health_check(name, &failover.health_check);
+            }
+            Urls::LoadBalance(balance) => {
+                self.health_check(name, &balance.health_check);
+                if balance.is_empty() {
+                    self.push(name, "load balance member list is empty");
+                }
+                match (balance.balance, &balance.hash_on) {
+                    (Strategy::ConsistentHash, None) => {
+                        self.push(name, "consistent_hash requires a hash_on key")
+                    }
+                    (Strategy::ConsistentHash, Some(_)) | (_, None) => (),
+                    (_, Some(_)) => self.push(name, "hash_on is only used by consistent_hash"),
+                }
+                for member in &balance.members {
+                    if member.weight() == 0 {
+                        self.push(name, format!("member {} has a weight of 0", member.url()));
+                    }
+                }
+            }
+            _ => (),
+        }
+        if let Some(breaker) = &proxy.circuit_breaker {
+            if breaker.consecutive_failures == 0 || breaker.min_requests == 0 {
+                self.push(name, "circuit breaker thresholds must be at least 1");
+            }
+            if breaker.error_rate == 0 || breaker.error_rate > 100 {
+                self.push(name, "circuit breaker error rate must be between 1 and 100");
+            }
+
+            // Members have one circuit across endpoints, so the endpoints must agree on it
+            for member in proxy.url.members() {
+                let first = match self.breakers.get(member.as_str()) {
+                    Some((route, existing)) if existing != breaker => route.clone(),
+                    Some(_) => continue,
+                    None => {
+                        self.breakers
+                            .insert(member.to_string(), (name.to_string(), breaker.clone()));
+                        continue;
+                    }
+                };
+                self.push(
+                    name,
+                    format!(
+                        "circuit breaker for {} differs from {}, which shares its circuit",
+                        member, first
+                    ),
+                );
+            }
+        }
+        for (field, rules) in [
+            ("request_headers", &proxy.request_headers),
+            ("response_headers", &proxy.response_headers),
+        ] {
+            for issue in rules.iter().flat_map(|r| r.issues()) {
+                self.push(name, format!("{} {}", field, issue));
+            }
+        }
+        self.security(name, &proxy.security);
+    }
+
+    fn health_check(&mut self, name: &str, health_check: &Option<HealthCheck>) {
+        if let Some(check) = health_check {
+            if check.healthy_threshold == 0 || check.unhealthy_threshold == 0 {
+                self.push(name, "health check thresholds must be at least 1");
+            }
+            if check.timeout == 0 {
+                self.push(name, "health check timeout must be greater than 0");
+            }
+            if check.timeout >= check.interval {
+                self.push(
+                    name,
+                    "health check timeout must be less than its interval, both in milliseconds",
+                );
+            }
+        }
+    }
+
+    fn security(&mut self, name: &str, security: &Option<Security>) {
+        let jwks = security
+            .as_ref()
+            .and_then(|s| s.client.as_ref())
+            .and_then(|c| c.jwks.as_ref());
+
+        if let Some(jwks) = jwks {
+            for url in jwks.urls() {
+                if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+                    self.push(name, format!("invalid jwks url {}", url));
+                }
+            }
+        }
+    }
+}
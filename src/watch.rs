@@ -0,0 +1,131 @@
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config_include::is_config_file;
+
+// Wait for changes to settle for 500 milliseconds before reloading
+const DEBOUNCE_DEFAULT: u64 = 500;
+
+// Watches files through their directories, as editors, kubernetes configmaps and secrets,
+// and certificate managers often replace files, rather than writing to them
+pub struct FileWatcher {
+    watcher: RecommendedWatcher,
+    watched: Arc<RwLock<Watched>>,
+    watching: HashSet<PathBuf>,
+    kind: &'static str,
+}
+
+// Files, and directories whose config files are all watched
+#[derive(Default)]
+struct Watched {
+    files: HashSet<PathBuf>,
+    directories: HashSet<PathBuf>,
+}
+
+// Changes to the watched files, ending when their watcher is dropped
+pub struct Changes(mpsc::Receiver<()>);
+
+impl FileWatcher {
+    // Kind names what is being watched in logs, such as config or certificate
+    pub fn new(kind: &'static str) -> notify::Result<(FileWatcher, Changes)> {
+        let watched: Arc<RwLock<Watched>> = Arc::default();
+
+        let (tx, rx) = mpsc::channel(16);
+        let paths = watched.clone();
+        let watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+                Ok(event) => {
+                    let paths = paths.read().unwrap();
+                    // Kubernetes configmaps and secrets are updated by swapping the ..data symlink
+                    let relevant = event.paths.iter().any(|p| match p.file_name() {
+                        Some(name) => {
+                            paths.files.contains(p)
+                                || (is_config_file(p)
+                                    && p.parent().is_some_and(|d| paths.directories.contains(d)))
+                                || name.to_string_lossy().starts_with("..")
+                        }
+                        None => false,
+                    });
+                    if relevant && !matches!(event.kind, EventKind::Access(_)) {
+                        let _ = tx.blocking_send(());
+                    }
+                }
+                Err(e) => log::error!("\"Error watching for {} changes: {}\"", kind, e),
+            })?;
+
+        let watcher = FileWatcher {
+            watcher,
+            watched,
+            watching: HashSet::new(),
+            kind,
+        };
+        Ok((watcher, Changes(rx)))
+    }
+
+    // Watch the directories of these paths, and stop watching directories which are no
+    // longer needed
+    pub fn watch(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        let mut watched = Watched::default();
+        for path in paths {
+            let path = absolute(&path);
+            if path.is_dir() {
+                watched.directories.insert(path);
+            } else {
+                watched.files.insert(path);
+            }
+        }
+
+        let mut wanted = watched.directories.clone();
+        wanted.extend(
+            watched
+                .files
+                .iter()
+                .filter_map(|f| f.parent())
+                .map(Path::to_path_buf),
+        );
+        *self.watched.write().unwrap() = watched;
+
+        for directory in wanted.difference(&self.watching) {
+            match self.watcher.watch(directory, RecursiveMode::NonRecursive) {
+                Ok(()) => log::info!(
+                    "\"Watching {} for {} changes\"",
+                    directory.display(),
+                    self.kind
+                ),
+                Err(e) => log::error!(
+                    "\"Unable to watch {} for {} changes: {}\"",
+                    directory.display(),
+                    self.kind,
+                    e
+                ),
+            }
+        }
+        for directory in self.watching.difference(&wanted) {
+            let _ = self.watcher.unwatch(directory);
+        }
+        self.watching = wanted;
+    }
+}
+
+impl Changes {
+    // Wait for the next change, once any events arriving while changes settle are drained
+    pub async fn next(&mut self) -> Option<()> {
+        self.0.recv().await?;
+        tokio::time::sleep(Duration::from_millis(DEBOUNCE_DEFAULT)).await;
+        while self.0.try_recv().is_ok() {}
+        Some(())
+    }
+}
+
+// Watch events name absolute paths, so relative paths are resolved against the working
+// directory, as files are read
+fn absolute(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(directory) if path.is_relative() => directory.join(path),
+        _ => path.to_path_buf(),
+    }
+}